@@ -0,0 +1,98 @@
+//! Compares building ClickHouse blocks from batched rows column by column
+//! with pushing rows of named values into block one by one
+//!
+//! Run with `cargo bench --bench batch`
+use std::{
+    hint::black_box,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chafka::{decoder::Row, inserter::TableBatch};
+use clickhouse_rs::{types::Value, Block};
+use rdkafka::{message::OwnedMessage, Timestamp};
+
+const ROWS: usize = 100_000;
+const ROUNDS: usize = 5;
+
+const COLUMNS: usize = 30;
+
+/// values of columns of the same types as decoders produce
+fn values(i: usize) -> Vec<Value> {
+    let mut values = Vec::with_capacity(COLUMNS);
+    for j in 0..5 {
+        values.push(Value::from(format!("value {i} of column {j}")));
+        values.push(Value::UInt64((i * j) as u64));
+        values.push(Value::Float64(i as f64 / (j + 1) as f64));
+        // every other value is NULL
+        values.push(Value::from(i.is_multiple_of(2).then_some(i as i32)));
+        values.push(Value::DateTime64(
+            1_700_000_000_000 + i as i64,
+            (3, chrono_tz::UTC),
+        ));
+        values.push(Value::from(
+            (!i.is_multiple_of(2)).then(|| format!("optional {i} of column {j}")),
+        ));
+    }
+    values
+}
+
+fn columns() -> Vec<String> {
+    (0..COLUMNS).map(|j| format!("column_{j}")).collect()
+}
+
+/// rows of named values are cloned and pushed into block, looking up columns by name
+fn row_by_row() -> Block {
+    let columns = columns();
+    let rows: Vec<Vec<(String, Value)>> = (0..ROWS)
+        .map(|i| columns.iter().cloned().zip(values(i)).collect())
+        .collect();
+    let mut block = Block::with_capacity(rows.len());
+    for row in &rows {
+        block.push(row.to_owned()).unwrap();
+    }
+    block
+}
+
+/// values are appended to batch by column, and block is built from columns
+fn columnar() -> Block {
+    let columns: Arc<[String]> = columns().into();
+    let msg = OwnedMessage::new(
+        None,
+        None,
+        String::from("t"),
+        Timestamp::NotAvailable,
+        0,
+        0,
+        None,
+    );
+    let mut batch = TableBatch::new("t");
+    for i in 0..ROWS {
+        let row = Row {
+            columns: columns.clone(),
+            values: values(i),
+        };
+        batch.push(&msg, row);
+    }
+    batch.blocks[0].block(0..ROWS).unwrap()
+}
+
+fn bench(name: &str, f: fn() -> Block) {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        let block = black_box(f());
+        best = best.min(start.elapsed());
+        assert_eq!(block.row_count(), ROWS);
+    }
+    println!(
+        "{name}: {ROWS} rows in {:?}, {:.0} rows/sec",
+        best,
+        ROWS as f64 / best.as_secs_f64()
+    );
+}
+
+fn main() {
+    bench("row by row", row_by_row);
+    bench("columnar", columnar);
+}
@@ -0,0 +1,126 @@
+//! Kafka consumer context of ingester
+//!
+//! Revoking partitions is deferred to ingester, so that it can flush rows of revoked partitions
+//! and commit their offsets before another consumer takes them over. Assigning partitions may be
+//! deferred as well, so that ingester can seek them to offsets stored in ClickHouse. Works with
+//! both eager and `cooperative-sticky` assignment strategies.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use rdkafka::{
+    client::NativeClient,
+    consumer::{ConsumerContext, DefaultConsumerContext},
+    statistics::Statistics,
+    types::RDKafkaRespErr,
+    ClientContext, TopicPartitionList,
+};
+use tokio::sync::Notify;
+
+use crate::metrics::Metrics;
+
+pub struct IngesterContext {
+    metrics: Arc<Metrics>,
+    /// partitions revoked by rebalance, but not yet given up by ingester
+    revoked: Mutex<Vec<(String, i32)>>,
+    /// partitions assigned by rebalance, but not yet taken by ingester
+    assigned: Mutex<Vec<(String, i32)>>,
+    defer_assign: bool,
+    rebalance: Notify,
+    /// when set, partitions are revoked immediately, e.g. when closing consumer
+    closing: AtomicBool,
+}
+
+impl IngesterContext {
+    /// creates context, which also defers assigning partitions if `defer_assign` is set
+    pub fn new(metrics: Arc<Metrics>, defer_assign: bool) -> Self {
+        IngesterContext {
+            metrics,
+            revoked: Mutex::new(Vec::new()),
+            assigned: Mutex::new(Vec::new()),
+            defer_assign,
+            rebalance: Notify::new(),
+            closing: AtomicBool::new(false),
+        }
+    }
+
+    /// waits until some partitions are revoked or assigned
+    pub async fn rebalanced(&self) {
+        self.rebalance.notified().await
+    }
+
+    /// returns partitions that are revoked since last call, which ingester must unassign
+    pub fn take_revoked(&self) -> Option<TopicPartitionList> {
+        take(&self.revoked)
+    }
+
+    /// returns partitions that are assigned since last call, which ingester must assign
+    pub fn take_assigned(&self) -> Option<TopicPartitionList> {
+        take(&self.assigned)
+    }
+
+    /// stops deferring rebalances, must be called before closing consumer
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+    }
+}
+
+impl ClientContext for IngesterContext {
+    fn stats(&self, statistics: Statistics) {
+        for (topic, t) in &statistics.topics {
+            for (partition, p) in &t.partitions {
+                // partition -1 is librdkafka's internal "unassigned" partition,
+                // lag is -1 until committed offset is known, and partitions which are not
+                // fetched are not assigned to this consumer anymore
+                if *partition < 0 || p.consumer_lag < 0 || p.fetch_state == "none" {
+                    continue;
+                }
+                self.metrics
+                    .set_consumer_lag(topic, *partition, p.consumer_lag);
+            }
+        }
+    }
+}
+
+impl ConsumerContext for IngesterContext {
+    fn rebalance(
+        &self,
+        native_client: &NativeClient,
+        err: RDKafkaRespErr,
+        tpl: &mut TopicPartitionList,
+    ) {
+        let deferred = match err {
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__REVOKE_PARTITIONS => &self.revoked,
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS if self.defer_assign => {
+                &self.assigned
+            }
+            _ => {
+                DefaultConsumerContext.rebalance(native_client, err, tpl);
+                return;
+            }
+        };
+        if self.closing.load(Ordering::Relaxed) {
+            DefaultConsumerContext.rebalance(native_client, err, tpl);
+            return;
+        }
+        // librdkafka waits for ingester to apply rebalance until `max.poll.interval.ms`
+        let mut deferred = deferred.lock().unwrap();
+        for e in tpl.elements() {
+            deferred.push((e.topic().to_string(), e.partition()));
+        }
+        self.rebalance.notify_one();
+    }
+}
+
+fn take(partitions: &Mutex<Vec<(String, i32)>>) -> Option<TopicPartitionList> {
+    let mut partitions = partitions.lock().unwrap();
+    if partitions.is_empty() {
+        return None;
+    }
+    let mut tpl = TopicPartitionList::new();
+    for (topic, partition) in partitions.drain(..) {
+        tpl.add_partition(&topic, partition);
+    }
+    Some(tpl)
+}
@@ -0,0 +1,145 @@
+//! Produces messages that cannot be ingested into dead-letter topic
+//!
+//! Original key, payload and headers are preserved, and the reason of failure
+//! along with the message source is attached as additional headers:
+//! * `chafka.error` - error description
+//! * `chafka.topic`, `chafka.partition`, `chafka.offset` - where message was consumed from
+//! * `chafka.timestamp` - original message timestamp (milliseconds since epoch)
+//!
+//! Rows rejected by ClickHouse are sent as JSON objects of column values, with
+//! `chafka.error`, `chafka.table` and the message source headers.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use clickhouse_rs::types::Value;
+use either::Either;
+use rdkafka::{
+    message::{Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Message,
+};
+use tokio::time::sleep;
+
+const DLQ_BACKOFF: Duration = Duration::from_secs(5);
+const DLQ_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct DeadLetterQueue {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl DeadLetterQueue {
+    /// creates producer, `kafka` properties override defaults
+    pub fn new(kafka_broker: &str, topic: String, kafka: &HashMap<String, String>) -> Result<Self> {
+        let mut cfg = ClientConfig::new();
+        cfg.set("bootstrap.servers", kafka_broker)
+            .set("enable.idempotence", "true");
+        for (k, v) in kafka {
+            cfg.set(k, v);
+        }
+        let producer: FutureProducer = cfg
+            .create()
+            .context("creating dead-letter kafka producer")?;
+        Ok(DeadLetterQueue { producer, topic })
+    }
+
+    /// sends message to dead-letter topic.
+    /// Keeps trying until kafka acknowledges the delivery, so that offset
+    /// of the failed message is never committed before it lands in DLQ
+    pub async fn send<M: Message>(&self, msg: &M, error: &str) {
+        let partition = msg.partition().to_string();
+        let offset = msg.offset().to_string();
+        let timestamp = msg.timestamp().to_millis().unwrap_or(-1).to_string();
+        loop {
+            let mut headers = OwnedHeaders::new();
+            if let Some(h) = msg.headers() {
+                for header in h.iter() {
+                    headers = headers.insert(header);
+                }
+            }
+            headers = headers
+                .insert(header("chafka.error", error))
+                .insert(header("chafka.topic", msg.topic()))
+                .insert(header("chafka.partition", &partition))
+                .insert(header("chafka.offset", &offset))
+                .insert(header("chafka.timestamp", &timestamp));
+            let mut record: FutureRecord<'_, [u8], [u8]> =
+                FutureRecord::to(&self.topic).headers(headers);
+            if let Some(key) = msg.key() {
+                record = record.key(key);
+            }
+            if let Some(payload) = msg.payload() {
+                record = record.payload(payload);
+            }
+            match self.producer.send(record, DLQ_QUEUE_TIMEOUT).await {
+                Ok(_) => return,
+                Err((e, _)) => {
+                    eprintln!(
+                        "sending message {}/{}/{} to dead-letter topic {}: {}",
+                        msg.topic(),
+                        partition,
+                        offset,
+                        self.topic,
+                        e
+                    );
+                    sleep(DLQ_BACKOFF).await;
+                }
+            }
+        }
+    }
+
+    /// sends row rejected by ClickHouse to dead-letter topic, keeps trying like `send`
+    pub async fn send_row(
+        &self,
+        table: &str,
+        row: impl Iterator<Item = (&String, Value)>,
+        source: (&(Arc<str>, i32), i64),
+        error: &str,
+    ) {
+        let ((topic, partition), offset) = source;
+        let (partition, offset) = (partition.to_string(), offset.to_string());
+        let payload = serde_json::Value::Object(
+            row.map(|(name, v)| (name.clone(), json_value(&v)))
+                .collect(),
+        )
+        .to_string();
+        loop {
+            let headers = OwnedHeaders::new()
+                .insert(header("chafka.error", error))
+                .insert(header("chafka.table", table))
+                .insert(header("chafka.topic", topic))
+                .insert(header("chafka.partition", &partition))
+                .insert(header("chafka.offset", &offset));
+            let record: FutureRecord<'_, [u8], str> = FutureRecord::to(&self.topic)
+                .headers(headers)
+                .payload(&payload);
+            match self.producer.send(record, DLQ_QUEUE_TIMEOUT).await {
+                Ok(_) => return,
+                Err((e, _)) => {
+                    eprintln!(
+                        "sending row of {}/{}/{} to dead-letter topic {}: {}",
+                        topic, partition, offset, self.topic, e
+                    );
+                    sleep(DLQ_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+fn json_value(v: &Value) -> serde_json::Value {
+    match v {
+        Value::String(s) => String::from_utf8_lossy(s).into(),
+        Value::Nullable(Either::Left(_)) => serde_json::Value::Null,
+        Value::Nullable(Either::Right(v)) => json_value(v),
+        Value::Array(_, items) => items.iter().map(json_value).collect(),
+        v => v.to_string().into(),
+    }
+}
+
+fn header<'a>(key: &'a str, value: &'a str) -> Header<'a, &'a str> {
+    Header {
+        key,
+        value: Some(value),
+    }
+}
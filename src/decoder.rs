@@ -1,24 +1,87 @@
 //! Manages decoders
 pub mod avro;
 pub mod example;
+pub mod json;
+pub mod protobuf;
+pub mod registry;
 pub mod static_avro_example;
+pub mod types;
 
-use std::sync::Arc;
+use std::{collections::HashMap, fmt, sync::Arc};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 
 use clickhouse_rs::types::Value;
 
 /// Confluent [header](https://docs.confluent.io/platform/current/schema-registry/fundamentals/serdes-develop/index.html#wire-format) length
 pub const CONFLUENT_HEADER_LEN: usize = 5;
 
-/// ClickHouse row - vector of columns, each column is tuple of its name and value
-pub type Row = Vec<(String, Value)>;
+/// ClickHouse row - values of columns, in order of their names
+pub struct Row {
+    /// names of columns, shared by rows of the same topic
+    pub columns: Arc<[String]>,
+    pub values: Vec<Value>,
+}
+
+impl Row {
+    /// returns value of named column
+    pub fn get(&self, column: &str) -> Option<&Value> {
+        let i = self.columns.iter().position(|c| c == column)?;
+        self.values.get(i)
+    }
+}
+
+/// Error of service decoder depends on, like schema registry, as opposed to malformed message.
+/// Messages failing with it are not sent to dead-letter topic, instead ingester fails without
+/// committing them, so that they are consumed again once the service is back
+#[derive(Debug)]
+pub struct Unavailable(pub &'static str);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is unavailable", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
 
-/// Decoder converts binary message from Kafka into ClickHouse row
+/// Decoder converts binary message from Kafka into values of ClickHouse columns
 pub trait Decoder {
     fn get_name(&self) -> String;
-    fn decode(&self, message: &[u8]) -> Result<Row, anyhow::Error>;
+    /// returns names of columns of decoded values
+    fn columns(&self) -> Vec<String>;
+    /// returns values of columns, in order of `columns`
+    fn decode(&self, message: &[u8]) -> Result<Vec<Value>, anyhow::Error>;
+}
+
+/// Decoders of ingested topics, created on first message of each topic,
+/// since decoders may depend on the topic, e.g. to look up its schema
+pub struct TopicDecoders {
+    name: String,
+    settings: Option<toml::Value>,
+    decoders: HashMap<String, Arc<dyn Decoder + Send + Sync>>,
+}
+
+impl TopicDecoders {
+    pub fn new(name: String, settings: Option<toml::Value>) -> Self {
+        TopicDecoders {
+            name,
+            settings,
+            decoders: HashMap::new(),
+        }
+    }
+
+    /// returns decoder of topic, creating it if needed
+    pub async fn get(&mut self, topic: &str) -> Result<Arc<dyn Decoder + Send + Sync>> {
+        if let Some(d) = self.decoders.get(topic) {
+            return Ok(d.clone());
+        }
+        let d = get_decoder(&self.name, self.settings.clone(), topic)
+            .await
+            .with_context(|| format!("loading decoder for topic {}", topic))?;
+        self.decoders.insert(topic.to_owned(), d.clone());
+        Ok(d)
+    }
 }
 
 /// Creates decoder of specified name.
@@ -34,6 +97,14 @@ pub async fn get_decoder(
             Some(s) => Ok(Arc::new(avro::new(topic, s.try_into()?).await?)),
             None => Err(anyhow!("avro config missing")),
         },
+        "json" => match decoder_settings {
+            Some(s) => Ok(Arc::new(json::new(s.try_into()?)?)),
+            None => Err(anyhow!("json config missing")),
+        },
+        "protobuf" => match decoder_settings {
+            Some(s) => Ok(Arc::new(protobuf::new(topic, s.try_into()?).await?)),
+            None => Err(anyhow!("protobuf config missing")),
+        },
         "test-avro" => Ok(Arc::new(static_avro_example::new()?)),
         _ => Err(anyhow!("unknown decoder {}", name)),
     }
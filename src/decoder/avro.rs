@@ -1,30 +1,64 @@
 //! generic avro message decoder.
 //!
-//! Takes schema either from configured file, or from [Schema Registry]
-//! using Confluent-compatible message [header].
+//! Takes reader schema either from configured file, or from the topic's subject in
+//! [Schema Registry] (see [registry] for subject and client settings).
+//! If registry is configured, writer schema of each message is fetched by its ID
+//! from Confluent-compatible message [header], cached, and resolved against
+//! the reader schema, so producers may evolve schema in compatible way.
 //!
-//! Supports all primitive types, maps and arrays of primitive types, nullable values,
+//! Supports all primitive types, maps and arrays, nullable values,
 //! and most of the logical types, like timestamps, UUIDs etc.
-//! Union types other than nullables, nested records, and decimals are not supported.
+//! Nested records are flattened into separate columns named by the full path of the field,
+//! joined with configurable separator (`.` by default, e.g. `parent.child`),
+//! and arrays of records are ingested into parallel array columns (`items.x`, `items.y`),
+//! compatible with ClickHouse [Nested] data structure.
+//! Options `field_names`, `include_fields` and `exclude_fields` refer to fields
+//! by their full dotted path, including or excluding a record includes or excludes all its fields.
+//! Decimals are ingested as `Decimal(P, S)` with precision up to 18 digits,
+//! fixed values are ingested as `FixedString(N)`; note that ClickHouse driver
+//! only accepts valid UTF-8 fixed values inside arrays and maps.
+//! Nullable unions (`["null", T]` or `[T, "null"]`) are ingested as values of `T`.
+//! Unions of several types are rejected, unless `split_unions` is enabled: then every
+//! branch of the union is ingested into separate column, named by the union field and
+//! the branch type, e.g. `value.string` and `value.long` (or `value.Event` for named types),
+//! and all columns but the one of the actual branch get zero values.
+//! ClickHouse `Variant` and `Dynamic` types are not supported by the ClickHouse driver yet.
+//! Missing values (nulls, fields of null records, other branches of split unions) are ingested
+//! as zero values of column type or configured `defaults`, keyed by field path;
+//! with `nullable` option, or for fields listed in `nullable_fields`, they are ingested
+//! into `Nullable` columns as NULLs instead (except arrays and maps, which cannot be NULL).
 //! All time types are assumed to be in UTC timezone.
 //!
 //! [Schema Registry]: https://docs.confluent.io/platform/current/schema-registry/index.html
 //! [header]: https://docs.confluent.io/platform/current/schema-registry/fundamentals/serdes-develop/index.html#wire-format
-use std::{collections::HashMap, fs, io::BufReader, sync::Arc, time::Duration};
+//! [Nested]: https://clickhouse.com/docs/en/sql-reference/data-types/nested-data-structures/nested
+use std::{
+    collections::HashMap,
+    fs,
+    io::BufReader,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 
-use apache_avro::{from_avro_datum, schema::RecordSchema, types::Value, Schema};
-use chrono::DateTime;
+use apache_avro::{
+    from_avro_datum,
+    schema::{
+        DecimalSchema, EnumSchema, FixedSchema, NamesRef, RecordSchema, ResolvedSchema, SchemaKind,
+    },
+    types::Value,
+    Decimal, Schema,
+};
 use chrono_tz::{self};
+use either::Either;
 
-use clickhouse_rs::types::{DateTimeType, SqlType, Value as CHValue};
-use reqwest::Url;
-use schema_registry_api::{SchemaRegistry, SchemaVersion, SubjectName};
+use clickhouse_rs::types::{DateTimeType, Decimal as CHDecimal, SqlType, Value as CHValue};
+use schema_registry_api::SchemaId;
 use serde::Deserialize;
-use uuid::Uuid;
+use tokio::runtime::Handle;
 
-use super::{Row, CONFLUENT_HEADER_LEN};
+use super::{json::json2ch, registry, types::zero_value, CONFLUENT_HEADER_LEN};
 
 #[derive(Deserialize)]
 pub struct Settings {
@@ -32,85 +66,229 @@ pub struct Settings {
     pub include_fields: Option<Vec<String>>,
     pub exclude_fields: Option<Vec<String>>,
     pub schema_file: Option<String>,
-    pub registry_url: Option<String>,
+    #[serde(flatten)]
+    pub registry: registry::Settings,
+    /// separator of nested record field names in column names (default: ".")
+    pub separator: Option<String>,
+    /// ingest branches of multi-type unions into separate columns (default: false)
+    pub split_unions: Option<bool>,
+    /// ingest missing values of all fields as NULLs (default: false)
+    pub nullable: Option<bool>,
+    /// paths of fields, which missing values are ingested as NULLs
+    pub nullable_fields: Option<Vec<String>>,
+    /// values to use for missing fields, by field path (default: zero value of column type)
+    pub defaults: Option<HashMap<String, toml::Value>>,
 }
 
 pub struct Decoder {
+    /// reader schema
     schema: Schema,
-    array_types: TypeMapping,
-    map_types: TypeMapping,
-    name_overrides: Vec<(String, String)>,
-    include_fields: Vec<String>,
-    exclude_fields: Vec<String>,
-    null_values: NullValues,
+    /// registry ID of reader schema, if it is taken from registry
+    schema_id: Option<SchemaId>,
+    registry: Option<registry::Registry>,
+    /// writer schemas fetched from registry by ID
+    writer_schemas: RwLock<HashMap<SchemaId, Arc<Schema>>>,
+    plan: Plan,
+    columns: Vec<Column>,
+}
+
+/// ClickHouse column produced by decoder
+struct Column {
+    name: String,
+    /// type of single value, as it appears in avro message
+    value_type: &'static SqlType,
+    /// type of column, i.e. value type wrapped into arrays for each enclosing array of records
+    column_type: &'static SqlType,
+    /// number of enclosing arrays of records
+    depth: usize,
+    /// value to use if field is missing
+    default: Option<CHValue>,
+}
+
+impl Column {
+    /// returns value of type `t` (either value or column type) to use if it is missing in message
+    fn missing(&self, t: &'static SqlType) -> CHValue {
+        match &self.default {
+            Some(d) if t == self.value_type => d.clone(),
+            _ => zero_value(t),
+        }
+    }
 }
 
-struct TypeMapping(Vec<(String, &'static SqlType)>);
-struct NullValues(Vec<(String, CHValue)>);
+/// describes how decoded avro value is mapped onto columns
+enum Plan {
+    Skip,
+    /// value is ingested into column with given index
+    Column(usize),
+    /// record which fields are flattened into separate columns
+    Record(Vec<Plan>),
+    /// array of records, which fields are ingested into parallel arrays.
+    /// Lists affected columns and types of their array elements
+    Records(Box<Plan>, Vec<(usize, &'static SqlType)>),
+    /// union split into columns, with plan for each branch
+    Union(Vec<Plan>),
+}
 
 impl Decoder {
-    fn avro2ch(&self, column_name: &str, v: Value) -> Result<CHValue, anyhow::Error> {
-        match v {
-            Value::Null => Err(anyhow!("unexpected null")),
-            Value::Record(_) => Err(anyhow!("unsupported nested record")),
-            Value::Boolean(x) => Ok(CHValue::from(x)),
-            Value::Int(x) => Ok(CHValue::from(x)),
-            Value::Long(x) => Ok(CHValue::from(x)),
-            Value::Float(x) => Ok(CHValue::from(x)),
-            Value::Double(x) => Ok(CHValue::from(x)),
-            Value::Bytes(x) => Ok(CHValue::from(x)),
-            Value::String(x) => Ok(CHValue::from(x)),
-            Value::Fixed(_, x) => Ok(CHValue::String(Arc::new(x))),
-            Value::Enum(_, y) => Ok(CHValue::from(y)),
-            Value::Union(_, v) => match *v {
-                Value::Null => match self.null_values.0.iter().find(|(n, _)| column_name.eq(n)) {
-                    None => Err(anyhow!("cannot find nullable field {}", column_name)),
-                    Some(x) => Ok(x.1.clone()),
-                },
-                v => self.avro2ch(column_name, v),
+    /// returns writer schema of message with given ID, or None if it is the reader schema
+    fn writer_schema(&self, id: SchemaId) -> Result<Option<Arc<Schema>>> {
+        let registry = match &self.registry {
+            Some(r) if self.schema_id != Some(id) => r,
+            _ => return Ok(None),
+        };
+        if let Some(s) = self.writer_schemas.read().unwrap().get(&id) {
+            return Ok(Some(s.clone()));
+        }
+        // decoding is synchronous, but new schemas are rare, so blocking the worker is fine
+        let schema =
+            tokio::task::block_in_place(|| Handle::current().block_on(registry.get_schema(id)))?;
+        let schema = Arc::new(
+            Schema::parse_str(&schema.schema).with_context(|| format!("parsing schema {}", id))?,
+        );
+        self.writer_schemas
+            .write()
+            .unwrap()
+            .insert(id, schema.clone());
+        Ok(Some(schema))
+    }
+
+    /// decodes value according to plan into columns of `out`
+    fn fill(&self, plan: &Plan, v: Value, out: &mut [Option<CHValue>]) -> Result<()> {
+        match (plan, v) {
+            (Plan::Union(branches), Value::Union(i, v)) => match branches.get(i as usize) {
+                Some(plan) => self.fill(plan, *v, out),
+                None => Err(anyhow!("unexpected union branch {}", i)),
             },
-            Value::Array(x) => {
-                let mut arr: Vec<CHValue> = Vec::new();
-                for elem in x {
-                    arr.push(self.avro2ch(column_name, elem)?)
-                }
-                let column_type = self.array_types.get_type(column_name).unwrap();
-                Ok(CHValue::Array(column_type, Arc::new(arr)))
+            (_, Value::Union(_, v)) => self.fill(plan, *v, out),
+            (Plan::Skip, _) | (_, Value::Null) => Ok(()),
+            (Plan::Column(i), v) => {
+                out[*i] = Some(avro2ch(v, self.columns[*i].value_type)?);
+                Ok(())
             }
-            Value::Map(x) => {
-                let mut m: HashMap<CHValue, CHValue> = HashMap::new();
-                for (k, v) in x {
-                    m.insert(CHValue::from(k), self.avro2ch(column_name, v)?);
+            (Plan::Record(fields), Value::Record(values)) => {
+                for (plan, (_, v)) in fields.iter().zip(values) {
+                    self.fill(plan, v, out)?;
                 }
-                let column_type = self.map_types.get_type(column_name).unwrap();
-                Ok(CHValue::Map(&SqlType::String, &column_type, Arc::new(m)))
-            }
-            Value::Date(x) => Ok(CHValue::Date(x as u16)),
-            Value::Decimal(_) => Err(anyhow!("unsupported decimal type")),
-            Value::TimeMillis(x) => Ok(CHValue::from(x)),
-            Value::TimeMicros(x) => Ok(CHValue::from(x)),
-            Value::TimestampMillis(x) => {
-                Ok(CHValue::from(DateTime::from_timestamp_millis(x).unwrap()))
+                Ok(())
             }
-            Value::TimestampMicros(x) => {
-                Ok(CHValue::from(DateTime::from_timestamp_micros(x).unwrap()))
+            (Plan::Records(plan, columns), Value::Array(items)) => {
+                let mut arrays = vec![Vec::with_capacity(items.len()); columns.len()];
+                let mut item_out = vec![None; out.len()];
+                for item in items {
+                    self.fill(plan, item, &mut item_out)?;
+                    for ((i, t), arr) in columns.iter().zip(arrays.iter_mut()) {
+                        arr.push(
+                            item_out[*i]
+                                .take()
+                                .unwrap_or_else(|| self.columns[*i].missing(t)),
+                        );
+                    }
+                }
+                for ((i, t), arr) in columns.iter().zip(arrays) {
+                    out[*i] = Some(CHValue::Array(t, Arc::new(arr)));
+                }
+                Ok(())
             }
-            Value::LocalTimestampMillis(x) => {
-                Ok(CHValue::from(DateTime::from_timestamp_millis(x).unwrap()))
+            (_, v) => Err(anyhow!("unexpected value {:?}", v)),
+        }
+    }
+}
+
+/// converts avro value into CH value of given type
+fn avro2ch(v: Value, t: &'static SqlType) -> Result<CHValue> {
+    match (v, t) {
+        (Value::Union(_, v), t) => avro2ch(*v, t),
+        (Value::Null, t) => Ok(zero_value(t)),
+        (v, SqlType::Nullable(t)) => Ok(CHValue::Nullable(Either::Right(Box::new(avro2ch(v, t)?)))),
+        (Value::Record(_), _) => Err(anyhow!("unsupported nested record")),
+        (Value::Boolean(x), _) => Ok(CHValue::from(x)),
+        (Value::Int(x), _) => Ok(CHValue::from(x)),
+        (Value::Long(x), _) => Ok(CHValue::from(x)),
+        (Value::Float(x), _) => Ok(CHValue::from(x)),
+        (Value::Double(x), _) => Ok(CHValue::from(x)),
+        (Value::Bytes(x), _) => Ok(CHValue::from(x)),
+        (Value::String(x), _) => Ok(CHValue::from(x)),
+        (Value::Fixed(_, x), _) => Ok(CHValue::String(Arc::new(x))),
+        (Value::Enum(_, y), _) => Ok(CHValue::from(y)),
+        (Value::Array(x), SqlType::Array(item_type)) => {
+            let mut arr: Vec<CHValue> = Vec::with_capacity(x.len());
+            for elem in x {
+                arr.push(check_fixed(avro2ch(elem, item_type)?, item_type)?)
             }
-            Value::LocalTimestampMicros(x) => {
-                Ok(CHValue::from(DateTime::from_timestamp_micros(x).unwrap()))
+            Ok(CHValue::Array(item_type, Arc::new(arr)))
+        }
+        (Value::Map(x), SqlType::Map(key_type, value_type)) => {
+            let mut m: HashMap<CHValue, CHValue> = HashMap::with_capacity(x.len());
+            for (k, v) in x {
+                m.insert(
+                    CHValue::from(k),
+                    check_fixed(avro2ch(v, value_type)?, value_type)?,
+                );
             }
-            Value::Duration(x) => {
-                // don't ask, programmers and time ¯\_(ツ)_/¯
-                let duration = Duration::from_millis(u32::from(x.millis()) as u64)
-                    + Duration::from_secs(86400 * u32::from(x.days()) as u64)
-                    + Duration::from_secs(30 * 86400 * u32::from(x.months()) as u64);
-                Ok(CHValue::from(duration.as_millis() as u64))
+            Ok(CHValue::Map(key_type, value_type, Arc::new(m)))
+        }
+        (Value::Date(x), _) => Ok(CHValue::Date(x as u16)),
+        (Value::Decimal(x), SqlType::Decimal(_, scale)) => Ok(CHValue::Decimal(CHDecimal::new(
+            decimal_to_i64(&x)?,
+            *scale,
+        ))),
+        (Value::TimeMillis(x), _) => Ok(CHValue::from(x)),
+        (Value::TimeMicros(x), _) => Ok(CHValue::from(x)),
+        (Value::TimestampMillis(x), _) | (Value::LocalTimestampMillis(x), _) => {
+            Ok(CHValue::DateTime64(x, (3, chrono_tz::UTC)))
+        }
+        (Value::TimestampMicros(x), _) | (Value::LocalTimestampMicros(x), _) => {
+            Ok(CHValue::DateTime64(x, (6, chrono_tz::UTC)))
+        }
+        (Value::Duration(x), _) => {
+            // don't ask, programmers and time ¯\_(ツ)_/¯
+            let duration = Duration::from_millis(u32::from(x.millis()) as u64)
+                + Duration::from_secs(86400 * u32::from(x.days()) as u64)
+                + Duration::from_secs(30 * 86400 * u32::from(x.months()) as u64);
+            Ok(CHValue::from(duration.as_millis() as u64))
+        }
+        (Value::Uuid(x), _) => Ok(CHValue::from(x)),
+        (v, t) => Err(anyhow!("cannot convert {:?} to {}", v, t)),
+    }
+}
+
+/// returns unscaled value of decimal
+fn decimal_to_i64(d: &Decimal) -> Result<i64> {
+    let bytes = Vec::<u8>::try_from(d)?;
+    let negative = bytes.first().is_some_and(|b| b & 0x80 != 0);
+    let sign = if negative { 0xffu8 } else { 0 };
+    let len = bytes.len();
+    // dropped bytes must only extend the sign, which is kept by the remaining ones
+    if len > 8
+        && (bytes[..len - 8].iter().any(|b| *b != sign) || bytes[len - 8] & 0x80 != sign & 0x80)
+    {
+        return Err(anyhow!("decimal value does not fit into 64 bits"));
+    }
+    let mut buf = [sign; 8];
+    let n = len.min(8);
+    buf[8 - n..].copy_from_slice(&bytes[len - n..]);
+    Ok(i64::from_be_bytes(buf))
+}
+
+/// ClickHouse driver converts fixed strings inside arrays and maps into rust strings,
+/// panicking on invalid UTF-8, so such values are rejected beforehand
+fn check_fixed(v: CHValue, t: &SqlType) -> Result<CHValue> {
+    let x = match (&v, t) {
+        (CHValue::String(x), SqlType::FixedString(_)) => x,
+        (CHValue::Nullable(Either::Right(x)), SqlType::Nullable(SqlType::FixedString(_))) => {
+            match x.as_ref() {
+                CHValue::String(x) => x,
+                _ => return Ok(v),
             }
-            Value::Uuid(x) => Ok(CHValue::from(x)),
         }
+        _ => return Ok(v),
+    };
+    if std::str::from_utf8(x).is_err() {
+        return Err(anyhow!(
+            "non UTF-8 fixed values are not supported inside arrays and maps"
+        ));
     }
+    Ok(v)
 }
 
 impl super::Decoder for Decoder {
@@ -118,181 +296,770 @@ impl super::Decoder for Decoder {
         String::from("avro")
     }
 
-    fn decode(&self, message: &[u8]) -> Result<Row> {
+    fn columns(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    fn decode(&self, message: &[u8]) -> Result<Vec<CHValue>> {
+        if message.len() < CONFLUENT_HEADER_LEN {
+            return Err(anyhow!("message is too short: {} bytes", message.len()));
+        }
+        if self.registry.is_some() && message[0] != 0 {
+            return Err(anyhow!("unknown magic byte {}", message[0]));
+        }
+        let id = u32::from_be_bytes(message[1..CONFLUENT_HEADER_LEN].try_into()?);
         let mut datum = BufReader::new(&message[CONFLUENT_HEADER_LEN..]);
-        let record;
-        let mut row = Row::new();
-        match from_avro_datum(&self.schema, &mut datum, None)? {
-            Value::Record(x) => record = x,
+        let value = match self.writer_schema(SchemaId::from(id as u64))? {
+            None => from_avro_datum(&self.schema, &mut datum, None)?,
+            Some(writer) => from_avro_datum(&writer, &mut datum, Some(&self.schema))?,
+        };
+        let record = match value {
+            Value::Record(x) => Value::Record(x),
             _ => return Err(anyhow!("avro message must be a record")),
         };
-        for (column, value) in record {
-            if self.exclude_fields.iter().any(|c| c == column.as_str()) {
-                continue;
-            }
-            if !self.include_fields.is_empty()
-                && !self.include_fields.iter().any(|c| c == column.as_str())
-            {
-                continue;
-            }
-            let v = self.avro2ch(&column, value)?;
-            let column_name = match self.name_overrides.iter().find(|m| m.0 == column) {
-                None => column,
-                Some((_, n)) => n.to_owned(),
-            };
-            row.push((column_name, v));
-        }
-        Ok(row)
+        let mut out = vec![None; self.columns.len()];
+        self.fill(&self.plan, record, &mut out)?;
+        Ok(self
+            .columns
+            .iter()
+            .zip(out)
+            .map(|(c, v)| v.unwrap_or_else(|| c.missing(c.column_type)))
+            .collect())
     }
 }
 
-impl TypeMapping {
-    fn new(r: &RecordSchema) -> Result<(Self, Self)> {
-        let mut map_types: Vec<(String, &SqlType)> = Vec::new();
-        let mut arr_types: Vec<(String, &SqlType)> = Vec::new();
-        for field in &r.fields {
-            match &field.schema {
-                Schema::Array(v) => arr_types.push((field.name.clone(), get_schema_type(v)?.0)),
-                Schema::Map(v) => map_types.push((field.name.clone(), get_schema_type(v)?.0)),
-                _ => (),
-            };
+/// walks through schema, checks it for compatibility and builds columns and decoding plan
+struct PlanBuilder<'a> {
+    names: &'a NamesRef<'a>,
+    separator: String,
+    name_overrides: HashMap<String, String>,
+    include_fields: Vec<String>,
+    exclude_fields: Vec<String>,
+    split_unions: bool,
+    nullable: bool,
+    nullable_fields: Vec<String>,
+    defaults: HashMap<String, toml::Value>,
+    columns: Vec<Column>,
+    /// names of records being walked through, to detect recursive schemas
+    records: Vec<String>,
+}
+
+impl<'a> PlanBuilder<'a> {
+    fn resolve(&self, s: &'a Schema) -> Result<&'a Schema> {
+        match s {
+            Schema::Ref { name } => match self.names.get(name) {
+                Some(s) => Ok(s),
+                None => Err(anyhow!("unknown type {}", name)),
+            },
+            s => Ok(s),
         }
-        Ok((TypeMapping(arr_types), TypeMapping(map_types)))
     }
 
-    fn get_type(&self, column: &str) -> Option<&'static SqlType> {
-        match self.0.iter().find(|x| x.0 == column) {
-            None => None,
-            Some((_, t)) => Some(t),
+    /// returns schema of nullable union's non-null variant, or schema itself
+    fn non_null(&self, s: &'a Schema) -> Result<&'a Schema> {
+        match self.resolve(s)? {
+            Schema::Union(union) => {
+                let mut schemas = union
+                    .variants()
+                    .iter()
+                    .filter(|s| !matches!(s, Schema::Null));
+                match (schemas.next(), schemas.next()) {
+                    (Some(s), None) => self.resolve(s),
+                    _ => Err(anyhow!(
+                        "union of several types is not supported, unless split_unions is enabled"
+                    )),
+                }
+            }
+            s => Ok(s),
         }
     }
-}
 
-pub async fn new(topic: &str, settings: Settings) -> Result<Decoder> {
-    let schema = get_schema(&topic, &settings).await?;
-    let mut name_overrides: Vec<(String, String)> = Vec::new();
-    let mut include_fields: Vec<String> = Vec::new();
-    let mut exclude_fields: Vec<String> = Vec::new();
-    if let Some(names) = settings.field_names {
-        names
-            .iter()
-            .for_each(|(k, v)| name_overrides.push((k.to_owned(), v.to_owned())));
-    }
-    if let Some(flds) = settings.include_fields {
-        flds.iter().for_each(|e| include_fields.push(e.to_owned()));
-    }
-    if let Some(flds) = settings.exclude_fields {
-        flds.iter().for_each(|e| exclude_fields.push(e.to_owned()));
-    }
-    match schema {
-        Schema::Record(record) => {
-            let (array_types, map_types, null_values) = analyze_schema(&record)?;
-            Ok(Decoder {
-                array_types,
-                map_types,
-                schema: Schema::Record(record),
-                name_overrides,
-                include_fields,
-                exclude_fields,
-                null_values,
-            })
+    /// returns path and column name of child field, or None if it is not selected
+    fn child(&self, path: &str, column: &str, name: &str) -> Option<(String, String)> {
+        let (path, column) = match path {
+            "" => (name.to_owned(), name.to_owned()),
+            _ => (
+                format!("{}.{}", path, name),
+                format!("{}{}{}", column, self.separator, name),
+            ),
+        };
+        if !self.is_selected(&path) {
+            return None;
+        }
+        match self.name_overrides.get(&path) {
+            None => Some((path, column)),
+            Some(n) => Some((path, n.to_owned())),
         }
-        _ => Err(anyhow!("avro schema root must be a record")),
     }
-}
 
-async fn get_schema(topic: &str, settings: &Settings) -> Result<Schema> {
-    match &settings.schema_file {
-        Some(f) => Ok(Schema::parse_str(&fs::read_to_string(f)?)?),
-        None => match &settings.registry_url {
-            None => Err(anyhow!("registry_url or schema_file must be specified")),
-            Some(registry_url) => {
-                let sr_client = SchemaRegistry::build_default(Url::parse(&registry_url)?)?;
-                let subject_name = format!("{topic}-value").parse::<SubjectName>()?;
-                let subject = sr_client
-                    .subject()
-                    .version(&subject_name, SchemaVersion::Latest)
-                    .await?;
-                match subject {
-                    None => Err(anyhow!("subject {} not found", subject_name)),
-                    Some(s) => Ok(Schema::parse_str(&s.schema)?),
+    fn is_selected(&self, path: &str) -> bool {
+        if self.exclude_fields.iter().any(|f| is_within(path, f)) {
+            return false;
+        }
+        self.include_fields.is_empty()
+            || self
+                .include_fields
+                .iter()
+                .any(|f| is_within(path, f) || is_within(f, path))
+    }
+
+    /// builds plan for value of schema `s` located at `path` and ingested into `column`,
+    /// `optional` tells if the value may be missing due to enclosing null record or union
+    fn plan(
+        &mut self,
+        s: &'a Schema,
+        path: &str,
+        column: &str,
+        depth: usize,
+        optional: bool,
+    ) -> Result<Plan> {
+        if let Schema::Union(union) = self.resolve(s)? {
+            let variants = union.variants();
+            let types = variants.iter().filter(|s| !matches!(s, Schema::Null));
+            if self.split_unions && types.count() > 1 {
+                let mut branches = Vec::with_capacity(variants.len());
+                for s in variants {
+                    let branch = match s {
+                        Schema::Null => None,
+                        s => self.child(path, column, &branch_name(self.resolve(s)?)),
+                    };
+                    match branch {
+                        None => branches.push(Plan::Skip),
+                        Some((path, column)) => {
+                            branches.push(self.plan(s, &path, &column, depth, true)?)
+                        }
+                    }
                 }
+                return Ok(Plan::Union(branches));
             }
-        },
+        }
+        let optional = optional || self.is_nullable(s)?;
+        match self.non_null(s)? {
+            Schema::Record(r) => {
+                if self.records.contains(&r.name.fullname(None)) {
+                    return Err(anyhow!("recursive record {} is not supported", r.name));
+                }
+                self.records.push(r.name.fullname(None));
+                let mut fields = Vec::with_capacity(r.fields.len());
+                for f in &r.fields {
+                    let Some((path, column)) = self.child(path, column, &f.name) else {
+                        fields.push(Plan::Skip);
+                        continue;
+                    };
+                    let plan = self
+                        .plan(&f.schema, &path, &column, depth, optional)
+                        .map_err(|e| anyhow!("field {}: {}", path, e))?;
+                    fields.push(plan);
+                }
+                self.records.pop();
+                Ok(Plan::Record(fields))
+            }
+            Schema::Array(items) if matches!(self.non_null(items)?, Schema::Record(_)) => {
+                let first = self.columns.len();
+                let plan = self.plan(items, path, column, depth + 1, false)?;
+                let columns = self.columns[first..]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (first + i, wrap_array(c.value_type, c.depth - depth - 1)))
+                    .collect();
+                Ok(Plan::Records(Box::new(plan), columns))
+            }
+            _ => {
+                let nullable =
+                    self.nullable || self.nullable_fields.iter().any(|f| is_within(path, f));
+                let value_type = match self.get_schema_type(s, nullable)? {
+                    t if nullable && optional => nullable_type(t),
+                    t => t,
+                };
+                let default = match self.defaults.remove(path) {
+                    None => None,
+                    Some(d) => Some(
+                        json2ch(&serde_json::to_value(&d)?, value_type, 1_000_000_000)
+                            .map_err(|e| anyhow!("invalid default: {}", e))?,
+                    ),
+                };
+                self.columns.push(Column {
+                    name: column.to_owned(),
+                    value_type,
+                    column_type: wrap_array(value_type, depth),
+                    depth,
+                    default,
+                });
+                Ok(Plan::Column(self.columns.len() - 1))
+            }
+        }
     }
-}
 
-/// checks schema for compatibility and returns type mappings for arrays and maps,
-/// as well as mapping of nullable fields to its zero values
-fn analyze_schema(s: &RecordSchema) -> Result<(TypeMapping, TypeMapping, NullValues)> {
-    let (array_types, map_types) = TypeMapping::new(s)?;
-    let mut null_values: Vec<(String, CHValue)> = Vec::new();
-    for fld in &s.fields {
-        match &fld.schema {
-            Schema::Record(_) => {
-                return Err(anyhow!(
-                    "field {}: nested records are not supported",
-                    fld.name
-                ))
-            }
-            Schema::Union(union) => {
-                let schemas = union.variants();
-                if schemas.len() != 2 {
+    /// tells if schema is a union with null
+    fn is_nullable(&self, s: &'a Schema) -> Result<bool> {
+        match self.resolve(s)? {
+            Schema::Union(union) => Ok(union.variants().iter().any(|s| matches!(s, Schema::Null))),
+            _ => Ok(false),
+        }
+    }
+
+    /// translates avro type into clickhouse type, making nullable unions `Nullable` if `nullable` is set
+    fn get_schema_type(&self, s: &'a Schema, nullable: bool) -> Result<&'static SqlType> {
+        if nullable && self.is_nullable(s)? {
+            return Ok(nullable_type(
+                self.get_schema_type(self.non_null(s)?, nullable)?,
+            ));
+        }
+        match self.non_null(s)? {
+            Schema::Boolean => Ok(&SqlType::Bool),
+            Schema::Int => Ok(&SqlType::Int32),
+            Schema::Long => Ok(&SqlType::Int64),
+            Schema::Float => Ok(&SqlType::Float32),
+            Schema::Double => Ok(&SqlType::Float64),
+            Schema::Bytes => Ok(&SqlType::String),
+            Schema::String => Ok(&SqlType::String),
+            Schema::Enum(_) => Ok(&SqlType::String),
+            Schema::Uuid => Ok(&SqlType::Uuid),
+            Schema::Fixed(s) => Ok(SqlType::FixedString(s.size).into()),
+            Schema::Decimal(DecimalSchema {
+                precision, scale, ..
+            }) => {
+                if *precision > 18 {
                     return Err(anyhow!(
-                        "field {}: only supported union type is [null, <type>]",
-                        fld.name
+                        "decimal precision {} is not supported, maximum is 18",
+                        precision
                     ));
                 }
-                match schemas[0] {
-                    Schema::Null => (),
-                    _ => {
-                        return Err(anyhow!(
-                            "field {}: only supported union type is [null, <type>]",
-                            fld.name
-                        ))
-                    }
-                }
-                null_values.push((fld.name.clone(), get_schema_type(&schemas[1])?.1))
+                Ok(SqlType::Decimal(*precision as u8, *scale as u8).into())
             }
-            _ => (),
+            Schema::Date => Ok(&SqlType::Date),
+            Schema::TimeMillis => Ok(&SqlType::Int32),
+            Schema::TimeMicros => Ok(&SqlType::Int64),
+            Schema::TimestampMillis | Schema::LocalTimestampMillis => Ok(&SqlType::DateTime(
+                DateTimeType::DateTime64(3, chrono_tz::UTC),
+            )),
+            Schema::TimestampMicros | Schema::LocalTimestampMicros => Ok(&SqlType::DateTime(
+                DateTimeType::DateTime64(6, chrono_tz::UTC),
+            )),
+            Schema::Duration => Ok(&SqlType::UInt64),
+            Schema::Array(items) => {
+                Ok(SqlType::Array(self.get_schema_type(items, nullable)?).into())
+            }
+            Schema::Map(values) => {
+                Ok(SqlType::Map(&SqlType::String, self.get_schema_type(values, nullable)?).into())
+            }
+            Schema::Record(r) => Err(anyhow!(
+                "record {} is not supported inside maps and arrays",
+                r.name
+            )),
+            _ => Err(anyhow!("unsupported type")),
         }
     }
-    Ok((array_types, map_types, NullValues(null_values)))
 }
 
-/// translates avro type into clickhouse type
-/// and returns relevant SqlType and its zero value
-fn get_schema_type(s: &Schema) -> Result<(&'static SqlType, CHValue)> {
+/// name of union branch: name of named types, or lowercase avro type otherwise
+fn branch_name(s: &Schema) -> String {
     match s {
-        Schema::Boolean => Ok((&SqlType::Bool, CHValue::from(false))),
-        Schema::Int => Ok((&SqlType::Int32, CHValue::Int32(0))),
-        Schema::Long => Ok((&SqlType::Int64, CHValue::Int64(0))),
-        Schema::Float => Ok((&SqlType::Float32, CHValue::Float32(0.0))),
-        Schema::Double => Ok((&SqlType::Float64, CHValue::Float64(0.0))),
-        Schema::Bytes => Ok((&SqlType::String, CHValue::from(Vec::<u8>::new()))),
-        Schema::String => Ok((&SqlType::String, CHValue::from(String::new()))),
-        Schema::Uuid => Ok((&SqlType::Uuid, CHValue::from(Uuid::nil()))),
-        //Schema::Fixed(s) => Ok((&SqlType::FixedString(s.size))),
-        Schema::Date => Ok((&SqlType::Date, CHValue::Date(0u16))),
-        Schema::TimeMillis => Ok((&SqlType::Int32, CHValue::DateTime(0, chrono_tz::UTC))),
-        Schema::TimeMicros => Ok((&SqlType::Int32, CHValue::DateTime(0, chrono_tz::UTC))),
-        Schema::TimestampMillis => Ok((
-            &SqlType::DateTime(DateTimeType::DateTime64(3, chrono_tz::UTC)),
-            CHValue::DateTime64(0, (3, chrono_tz::UTC)),
-        )),
-        Schema::TimestampMicros => Ok((
-            &SqlType::DateTime(DateTimeType::DateTime64(6, chrono_tz::UTC)),
-            CHValue::DateTime64(0, (6, chrono_tz::UTC)),
-        )),
-        Schema::LocalTimestampMillis => Ok((
-            &SqlType::DateTime(DateTimeType::DateTime64(3, chrono_tz::UTC)),
-            CHValue::DateTime64(0, (3, chrono_tz::UTC)),
-        )),
-        Schema::LocalTimestampMicros => Ok((
-            &SqlType::DateTime(DateTimeType::DateTime64(6, chrono_tz::UTC)),
-            CHValue::DateTime64(0, (6, chrono_tz::UTC)),
-        )),
-        Schema::Duration => Ok((&SqlType::Int64, CHValue::UInt64(0))),
-        _ => Err(anyhow!("unsupported type")),
+        Schema::Record(RecordSchema { name, .. })
+        | Schema::Enum(EnumSchema { name, .. })
+        | Schema::Fixed(FixedSchema { name, .. }) => name.name.clone(),
+        s => format!("{:?}", SchemaKind::from(s)).to_lowercase(),
+    }
+}
+
+fn is_within(path: &str, parent: &str) -> bool {
+    path == parent || (path.starts_with(parent) && path[parent.len()..].starts_with('.'))
+}
+
+/// wraps type into `Nullable`, unless it cannot be inside one
+fn nullable_type(t: &'static SqlType) -> &'static SqlType {
+    match t {
+        SqlType::Array(_) | SqlType::Map(_, _) | SqlType::Nullable(_) => t,
+        t => SqlType::Nullable(t).into(),
+    }
+}
+
+fn wrap_array(t: &'static SqlType, depth: usize) -> &'static SqlType {
+    (0..depth).fold(t, |t, _| SqlType::Array(t).into())
+}
+
+pub async fn new(topic: &str, settings: Settings) -> Result<Decoder> {
+    let registry = settings.registry.client()?;
+    let (schema, schema_id) = get_schema(topic, &settings, registry.as_ref()).await?;
+    from_schema(schema, schema_id, registry, settings)
+}
+
+/// builds decoder of messages with given reader schema
+fn from_schema(
+    schema: Schema,
+    schema_id: Option<SchemaId>,
+    registry: Option<registry::Registry>,
+    settings: Settings,
+) -> Result<Decoder> {
+    if !matches!(schema, Schema::Record(_)) {
+        return Err(anyhow!("avro schema root must be a record"));
+    }
+    let resolved = ResolvedSchema::try_from(&schema)?;
+    let mut builder = PlanBuilder {
+        names: resolved.get_names(),
+        separator: settings.separator.unwrap_or(String::from(".")),
+        name_overrides: settings.field_names.unwrap_or_default(),
+        include_fields: settings.include_fields.unwrap_or_default(),
+        exclude_fields: settings.exclude_fields.unwrap_or_default(),
+        split_unions: settings.split_unions.unwrap_or(false),
+        nullable: settings.nullable.unwrap_or(false),
+        nullable_fields: settings.nullable_fields.unwrap_or_default(),
+        defaults: settings.defaults.unwrap_or_default(),
+        columns: Vec::new(),
+        records: Vec::new(),
+    };
+    let plan = builder.plan(&schema, "", "", 0, false)?;
+    if let Some(path) = builder.defaults.keys().next() {
+        return Err(anyhow!("default is set for unknown field {}", path));
+    }
+    let columns = builder.columns;
+    Ok(Decoder {
+        schema,
+        schema_id,
+        registry,
+        writer_schemas: RwLock::new(HashMap::new()),
+        plan,
+        columns,
+    })
+}
+
+/// returns reader schema and its registry ID
+async fn get_schema(
+    topic: &str,
+    settings: &Settings,
+    registry: Option<&registry::Registry>,
+) -> Result<(Schema, Option<SchemaId>)> {
+    match &settings.schema_file {
+        Some(f) => Ok((Schema::parse_str(&fs::read_to_string(f)?)?, None)),
+        None => match registry {
+            None => Err(anyhow!("registry_url or schema_file must be specified")),
+            Some(client) => {
+                let subject = client.get_subject(&settings.registry, topic, None).await?;
+                Ok((Schema::parse_str(&subject.schema)?, Some(subject.id)))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder as _;
+    use apache_avro::to_avro_datum;
+
+    const EVENT: &str = r#"{
+        "type": "record",
+        "name": "Event",
+        "fields": [
+            {"name": "id", "type": "long"},
+            {"name": "user", "type": {
+                "type": "record",
+                "name": "User",
+                "fields": [
+                    {"name": "name", "type": "string"},
+                    {"name": "address", "type": ["null", {
+                        "type": "record",
+                        "name": "Address",
+                        "fields": [
+                            {"name": "city", "type": "string"},
+                            {"name": "zip", "type": "int"}
+                        ]
+                    }]}
+                ]
+            }},
+            {"name": "items", "type": {"type": "array", "items": {
+                "type": "record",
+                "name": "Item",
+                "fields": [
+                    {"name": "sku", "type": "string"},
+                    {"name": "qty", "type": "int"}
+                ]
+            }}}
+        ]
+    }"#;
+
+    fn decoder(schema: &str, settings: &str) -> Result<Decoder> {
+        let settings: Settings = toml::from_str(settings)?;
+        from_schema(Schema::parse_str(schema)?, None, None, settings)
+    }
+
+    fn record(fields: Vec<(&str, Value)>) -> Value {
+        Value::Record(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    /// encodes value with reader schema of decoder and decodes it back
+    fn decode(d: &Decoder, v: Value) -> Result<Vec<CHValue>> {
+        let mut message = vec![0; CONFLUENT_HEADER_LEN];
+        message.extend(to_avro_datum(&d.schema, v)?);
+        d.decode(&message)
+    }
+
+    fn event(address: Option<(&str, i32)>) -> Value {
+        let address = match address {
+            None => Value::Union(0, Box::new(Value::Null)),
+            Some((city, zip)) => Value::Union(
+                1,
+                Box::new(record(vec![
+                    ("city", Value::String(city.to_owned())),
+                    ("zip", Value::Int(zip)),
+                ])),
+            ),
+        };
+        let item = |sku: &str, qty| {
+            record(vec![
+                ("sku", Value::String(sku.to_owned())),
+                ("qty", Value::Int(qty)),
+            ])
+        };
+        record(vec![
+            ("id", Value::Long(1)),
+            (
+                "user",
+                record(vec![
+                    ("name", Value::String("bob".to_owned())),
+                    ("address", address),
+                ]),
+            ),
+            ("items", Value::Array(vec![item("a", 1), item("b", 2)])),
+        ])
+    }
+
+    #[test]
+    fn nested_records() {
+        let d = decoder(EVENT, "").unwrap();
+        assert_eq!(
+            d.columns(),
+            [
+                "id",
+                "user.name",
+                "user.address.city",
+                "user.address.zip",
+                "items.sku",
+                "items.qty"
+            ]
+        );
+        let strings = |x: &[&str]| {
+            CHValue::Array(
+                &SqlType::String,
+                Arc::new(x.iter().map(|s| CHValue::from(*s)).collect()),
+            )
+        };
+        let ints = |x: &[i32]| {
+            CHValue::Array(
+                &SqlType::Int32,
+                Arc::new(x.iter().map(|i| CHValue::from(*i)).collect()),
+            )
+        };
+        assert_eq!(
+            decode(&d, event(Some(("paris", 75000)))).unwrap(),
+            [
+                CHValue::from(1i64),
+                CHValue::from("bob"),
+                CHValue::from("paris"),
+                CHValue::from(75000),
+                strings(&["a", "b"]),
+                ints(&[1, 2]),
+            ]
+        );
+        // fields of null record get zero values
+        assert_eq!(
+            decode(&d, event(None)).unwrap(),
+            [
+                CHValue::from(1i64),
+                CHValue::from("bob"),
+                CHValue::from(""),
+                CHValue::from(0),
+                strings(&["a", "b"]),
+                ints(&[1, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn separator() {
+        let d = decoder(EVENT, r#"separator = "_""#).unwrap();
+        assert_eq!(
+            d.columns(),
+            [
+                "id",
+                "user_name",
+                "user_address_city",
+                "user_address_zip",
+                "items_sku",
+                "items_qty"
+            ]
+        );
+    }
+
+    #[test]
+    fn selected_fields() {
+        // including record includes all its fields
+        let d = decoder(EVENT, r#"include_fields = ["id", "user.address"]"#).unwrap();
+        assert_eq!(d.columns(), ["id", "user.address.city", "user.address.zip"]);
+        assert_eq!(
+            decode(&d, event(Some(("paris", 75000)))).unwrap(),
+            [
+                CHValue::from(1i64),
+                CHValue::from("paris"),
+                CHValue::from(75000)
+            ]
+        );
+        let d = decoder(
+            EVENT,
+            r#"
+            exclude_fields = ["user.address.zip", "items"]
+            [field_names]
+            "user.address.city" = "city"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(d.columns(), ["id", "user.name", "city"]);
+        assert_eq!(
+            decode(&d, event(Some(("paris", 75000)))).unwrap(),
+            [
+                CHValue::from(1i64),
+                CHValue::from("bob"),
+                CHValue::from("paris")
+            ]
+        );
+        // paths are full, not just field names
+        let d = decoder(EVENT, r#"exclude_fields = ["city", "address"]"#).unwrap();
+        assert_eq!(d.columns().len(), 6);
+    }
+
+    fn decimal(bytes: &[u8]) -> Decimal {
+        Decimal::from(bytes.to_vec())
+    }
+
+    #[test]
+    fn decimals() {
+        assert_eq!(decimal_to_i64(&decimal(&[0x00])).unwrap(), 0);
+        assert_eq!(decimal_to_i64(&decimal(&[0x01, 0x00])).unwrap(), 256);
+        assert_eq!(decimal_to_i64(&decimal(&[0xff])).unwrap(), -1);
+        assert_eq!(decimal_to_i64(&decimal(&[0xff, 0x00])).unwrap(), -256);
+        assert_eq!(
+            decimal_to_i64(&decimal(&i64::MAX.to_be_bytes())).unwrap(),
+            i64::MAX
+        );
+        assert_eq!(
+            decimal_to_i64(&decimal(&i64::MIN.to_be_bytes())).unwrap(),
+            i64::MIN
+        );
+        // sign extended beyond 64 bits
+        let mut bytes = vec![0xff; 4];
+        bytes.extend((-5i64).to_be_bytes());
+        assert_eq!(decimal_to_i64(&decimal(&bytes)).unwrap(), -5);
+        let mut bytes = vec![0x00; 4];
+        bytes.extend(5i64.to_be_bytes());
+        assert_eq!(decimal_to_i64(&decimal(&bytes)).unwrap(), 5);
+    }
+
+    #[test]
+    fn decimals_out_of_range() {
+        // 2^64
+        let mut bytes = vec![0x01];
+        bytes.extend([0; 8]);
+        assert!(decimal_to_i64(&decimal(&bytes)).is_err());
+        // 2^63 doesn't fit, although dropped byte is zero
+        let mut bytes = vec![0x00];
+        bytes.extend(i64::MIN.to_be_bytes());
+        assert!(decimal_to_i64(&decimal(&bytes)).is_err());
+        // -2^63 - 1
+        let mut bytes = vec![0xff];
+        bytes.extend(i64::MAX.to_be_bytes());
+        assert!(decimal_to_i64(&decimal(&bytes)).is_err());
+    }
+
+    #[test]
+    fn nullable_unions() {
+        let schema = r#"{
+            "type": "record",
+            "name": "Nullables",
+            "fields": [
+                {"name": "a", "type": ["null", "string"]},
+                {"name": "b", "type": ["long", "null"]}
+            ]
+        }"#;
+        let d = decoder(schema, "").unwrap();
+        assert_eq!(d.columns(), ["a", "b"]);
+        let v = record(vec![
+            (
+                "a",
+                Value::Union(1, Box::new(Value::String("x".to_owned()))),
+            ),
+            ("b", Value::Union(0, Box::new(Value::Long(5)))),
+        ]);
+        assert_eq!(
+            decode(&d, v).unwrap(),
+            [CHValue::from("x"), CHValue::from(5i64)]
+        );
+        let v = record(vec![
+            ("a", Value::Union(0, Box::new(Value::Null))),
+            ("b", Value::Union(1, Box::new(Value::Null))),
+        ]);
+        assert_eq!(
+            decode(&d, v).unwrap(),
+            [CHValue::from(""), CHValue::from(0i64)]
+        );
+        // nullable unions are not split
+        let d = decoder(schema, "split_unions = true").unwrap();
+        assert_eq!(d.columns(), ["a", "b"]);
+        let v = record(vec![
+            ("a", Value::Union(0, Box::new(Value::Null))),
+            ("b", Value::Union(0, Box::new(Value::Long(5)))),
+        ]);
+        assert_eq!(
+            decode(&d, v).unwrap(),
+            [CHValue::from(""), CHValue::from(5i64)]
+        );
+    }
+
+    #[test]
+    fn split_unions() {
+        let schema = r#"{
+            "type": "record",
+            "name": "Unions",
+            "fields": [
+                {"name": "a", "type": ["null", "string"]},
+                {"name": "b", "type": ["long", "null"]},
+                {"name": "value", "type": ["null", "string", "long", {
+                    "type": "record",
+                    "name": "Point",
+                    "fields": [{"name": "x", "type": "int"}]
+                }]}
+            ]
+        }"#;
+        let err = decoder(schema, "").err().unwrap();
+        assert!(err.to_string().contains("split_unions"), "{}", err);
+
+        let d = decoder(schema, "split_unions = true").unwrap();
+        assert_eq!(
+            d.columns(),
+            ["a", "b", "value.string", "value.long", "value.Point.x"]
+        );
+        let v = |i, x| {
+            record(vec![
+                ("a", Value::Union(0, Box::new(Value::Null))),
+                ("b", Value::Union(1, Box::new(Value::Null))),
+                ("value", Value::Union(i, Box::new(x))),
+            ])
+        };
+        assert_eq!(
+            decode(&d, v(2, Value::Long(5))).unwrap()[2..],
+            [CHValue::from(""), CHValue::from(5i64), CHValue::from(0)]
+        );
+        assert_eq!(
+            decode(&d, v(1, Value::String("x".to_owned()))).unwrap()[2..],
+            [CHValue::from("x"), CHValue::from(0i64), CHValue::from(0)]
+        );
+        assert_eq!(
+            decode(&d, v(3, record(vec![("x", Value::Int(7))]))).unwrap()[2..],
+            [CHValue::from(""), CHValue::from(0i64), CHValue::from(7)]
+        );
+        // null branch leaves all columns with zero values
+        assert_eq!(
+            decode(&d, v(0, Value::Null)).unwrap(),
+            [
+                CHValue::from(""),
+                CHValue::from(0i64),
+                CHValue::from(""),
+                CHValue::from(0i64),
+                CHValue::from(0)
+            ]
+        );
+    }
+
+    const OPTIONALS: &str = r#"{
+        "type": "record",
+        "name": "Optionals",
+        "fields": [
+            {"name": "a", "type": ["null", "string"]},
+            {"name": "b", "type": ["null", "long"]}
+        ]
+    }"#;
+
+    fn optionals(a: Option<&str>, b: Option<i64>) -> Value {
+        let a = match a {
+            None => Value::Union(0, Box::new(Value::Null)),
+            Some(a) => Value::Union(1, Box::new(Value::String(a.to_owned()))),
+        };
+        let b = match b {
+            None => Value::Union(0, Box::new(Value::Null)),
+            Some(b) => Value::Union(1, Box::new(Value::Long(b))),
+        };
+        record(vec![("a", a), ("b", b)])
+    }
+
+    #[test]
+    fn nullable_columns() {
+        let d = decoder(OPTIONALS, r#"nullable_fields = ["a"]"#).unwrap();
+        assert_eq!(
+            decode(&d, optionals(None, None)).unwrap(),
+            [
+                CHValue::Nullable(Either::Left(&SqlType::String)),
+                CHValue::from(0i64)
+            ]
+        );
+        assert_eq!(
+            decode(&d, optionals(Some("x"), Some(5))).unwrap(),
+            [
+                CHValue::Nullable(Either::Right(Box::new(CHValue::from("x")))),
+                CHValue::from(5i64)
+            ]
+        );
+        let d = decoder(OPTIONALS, "nullable = true").unwrap();
+        assert_eq!(
+            decode(&d, optionals(None, None)).unwrap(),
+            [
+                CHValue::Nullable(Either::Left(&SqlType::String)),
+                CHValue::Nullable(Either::Left(&SqlType::Int64))
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        let d = decoder(
+            OPTIONALS,
+            r#"
+            [defaults]
+            a = "none"
+            b = 42
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            decode(&d, optionals(None, None)).unwrap(),
+            [CHValue::from("none"), CHValue::from(42i64)]
+        );
+        assert_eq!(
+            decode(&d, optionals(Some("x"), Some(5))).unwrap(),
+            [CHValue::from("x"), CHValue::from(5i64)]
+        );
+        // non-nullable fields still get defaults
+        let d = decoder(
+            OPTIONALS,
+            r#"
+            nullable_fields = ["a"]
+            [defaults]
+            b = 42
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            decode(&d, optionals(None, None)).unwrap(),
+            [
+                CHValue::Nullable(Either::Left(&SqlType::String)),
+                CHValue::from(42i64)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_defaults() {
+        let err = decoder(OPTIONALS, "[defaults]\nb = \"x\"").err().unwrap();
+        assert!(err.to_string().contains("invalid default"), "{}", err);
+        let err = decoder(OPTIONALS, "[defaults]\nc = 1").err().unwrap();
+        assert!(err.to_string().contains("unknown field c"), "{}", err);
     }
 }
@@ -4,8 +4,6 @@ use clickhouse_rs::types::Value;
 use serde::Deserialize;
 use uuid::Uuid;
 
-use super::Row;
-
 pub struct Decoder;
 
 /// message serialized in Kafka
@@ -19,11 +17,11 @@ impl super::Decoder for Decoder {
     fn get_name(&self) -> String {
         String::from("example")
     }
-    fn decode(&self, message: &[u8]) -> Result<Row, anyhow::Error> {
+    fn columns(&self) -> Vec<String> {
+        vec![String::from("id"), String::from("v")]
+    }
+    fn decode(&self, message: &[u8]) -> Result<Vec<Value>, anyhow::Error> {
         let r: Entry = serde_json::from_slice(message)?;
-        Ok(vec![
-            (String::from("id"), Value::from(r.key)),
-            (String::from("v"), Value::from(r.value)),
-        ])
+        Ok(vec![Value::from(r.key), Value::from(r.value)])
     }
 }
@@ -0,0 +1,406 @@
+//! generic JSON message decoder.
+//!
+//! Unlike other generic decoders, JSON messages carry no schema, so every column
+//! is configured explicitly with its ClickHouse type and location of its value
+//! in the message, either as [JSON pointer] (`/meta/tags/0`) or dotted path (`meta.source`):
+//! ```toml
+//! custom.columns = [
+//!     { name = "id", type = "UUID", path = "/key" },
+//!     { name = "v", type = "Int64", path = "value", default = 0 },
+//!     { name = "ts", type = "DateTime64(3)", path = "meta.time", epoch_unit = "ms" },
+//!     { name = "tags", type = "Array(String)" },
+//!     { name = "comment", type = "Nullable(String)" },
+//! ]
+//! ```
+//! Values are coerced to the column type where possible: numbers may be passed as strings,
+//! dates and timestamps may be either ISO-8601 strings or numeric epoch timestamps,
+//! arrays and objects are ingested into `Array` and `Map` columns, any JSON value
+//! is ingested into `String` column as is.
+//! Missing or null values are replaced by the column default, if it is configured,
+//! or by NULL for `Nullable` columns, otherwise the message fails to decode.
+//!
+//! [JSON pointer]: https://datatracker.ietf.org/doc/html/rfc6901
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use clickhouse_rs::types::{DateTimeType, SqlType, Value as CHValue};
+use either::Either;
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::types::parse_type;
+
+const NANOS_IN_DAY: i64 = 86400 * 1_000_000_000;
+
+#[derive(Deserialize)]
+pub struct Settings {
+    pub columns: Vec<ColumnSettings>,
+}
+
+#[derive(Deserialize)]
+pub struct ColumnSettings {
+    /// name of ClickHouse column
+    pub name: String,
+    /// ClickHouse type of column
+    #[serde(rename = "type")]
+    pub column_type: String,
+    /// JSON pointer or dotted path of the value (default: top-level field named as column)
+    pub path: Option<String>,
+    /// value to use if field is missing or null
+    pub default: Option<toml::Value>,
+    /// unit of numeric timestamps: "s", "ms", "us" or "ns" (default: "s")
+    pub epoch_unit: Option<String>,
+}
+
+pub struct Decoder {
+    columns: Vec<Column>,
+}
+
+struct Column {
+    name: String,
+    pointer: String,
+    sql_type: &'static SqlType,
+    default: Option<CHValue>,
+    /// nanoseconds in unit of numeric timestamps
+    epoch_unit: i64,
+}
+
+impl super::Decoder for Decoder {
+    fn get_name(&self) -> String {
+        String::from("json")
+    }
+
+    fn columns(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    fn decode(&self, message: &[u8]) -> Result<Vec<CHValue>> {
+        let doc: Value = serde_json::from_slice(message)?;
+        let mut row = Vec::with_capacity(self.columns.len());
+        for column in &self.columns {
+            let v = match doc.pointer(&column.pointer) {
+                Some(v) if !v.is_null() => json2ch(v, column.sql_type, column.epoch_unit)
+                    .with_context(|| format!("column {}", column.name))?,
+                _ => match (&column.default, column.sql_type) {
+                    (Some(d), _) => d.clone(),
+                    (None, SqlType::Nullable(t)) => CHValue::Nullable(Either::Left(t)),
+                    (None, _) => return Err(anyhow!("column {}: value is missing", column.name)),
+                },
+            };
+            row.push(v);
+        }
+        Ok(row)
+    }
+}
+
+/// converts JSON value into CH value of given type
+pub fn json2ch(v: &Value, t: &'static SqlType, epoch_unit: i64) -> Result<CHValue> {
+    match t {
+        SqlType::Nullable(inner) => match v {
+            Value::Null => Ok(CHValue::Nullable(Either::Left(inner))),
+            v => Ok(CHValue::Nullable(Either::Right(Box::new(json2ch(
+                v, inner, epoch_unit,
+            )?)))),
+        },
+        SqlType::Bool => match v {
+            Value::Bool(x) => Ok(CHValue::from(*x)),
+            Value::Number(_) => Ok(CHValue::from(to_f64(v)? != 0.0)),
+            Value::String(s) => match s.as_str() {
+                "true" | "1" => Ok(CHValue::from(true)),
+                "false" | "0" => Ok(CHValue::from(false)),
+                _ => Err(anyhow!("cannot convert {} to Bool", v)),
+            },
+            _ => Err(anyhow!("cannot convert {} to Bool", v)),
+        },
+        SqlType::UInt8 => Ok(CHValue::from(u8::try_from(to_i128(v)?)?)),
+        SqlType::UInt16 => Ok(CHValue::from(u16::try_from(to_i128(v)?)?)),
+        SqlType::UInt32 => Ok(CHValue::from(u32::try_from(to_i128(v)?)?)),
+        SqlType::UInt64 => Ok(CHValue::from(u64::try_from(to_i128(v)?)?)),
+        SqlType::Int8 => Ok(CHValue::from(i8::try_from(to_i128(v)?)?)),
+        SqlType::Int16 => Ok(CHValue::from(i16::try_from(to_i128(v)?)?)),
+        SqlType::Int32 => Ok(CHValue::from(i32::try_from(to_i128(v)?)?)),
+        SqlType::Int64 => Ok(CHValue::from(i64::try_from(to_i128(v)?)?)),
+        SqlType::Float32 => Ok(CHValue::from(to_f64(v)? as f32)),
+        SqlType::Float64 => Ok(CHValue::from(to_f64(v)?)),
+        SqlType::String => match v {
+            Value::String(s) => Ok(CHValue::from(s.as_str())),
+            v => Ok(CHValue::from(v.to_string())),
+        },
+        SqlType::Uuid => match v {
+            Value::String(s) => Ok(CHValue::from(Uuid::parse_str(s)?)),
+            _ => Err(anyhow!("cannot convert {} to UUID", v)),
+        },
+        SqlType::Date => {
+            let days = to_nanos(v, epoch_unit)?.div_euclid(NANOS_IN_DAY);
+            Ok(CHValue::Date(u16::try_from(days)?))
+        }
+        SqlType::DateTime(DateTimeType::DateTime64(precision, tz)) => {
+            let nanos = to_nanos(v, epoch_unit)?;
+            let value = nanos / 10i64.pow(9 - precision);
+            Ok(CHValue::DateTime64(value, (*precision, *tz)))
+        }
+        SqlType::DateTime(_) => {
+            let seconds = to_nanos(v, epoch_unit)?.div_euclid(1_000_000_000);
+            Ok(CHValue::DateTime(u32::try_from(seconds)?, chrono_tz::UTC))
+        }
+        SqlType::Array(inner) => match v {
+            Value::Array(values) => {
+                let mut arr = Vec::with_capacity(values.len());
+                for v in values {
+                    arr.push(json2ch(v, inner, epoch_unit)?);
+                }
+                Ok(CHValue::Array(inner, Arc::new(arr)))
+            }
+            _ => Err(anyhow!("cannot convert {} to Array", v)),
+        },
+        SqlType::Map(key_type, value_type) => match v {
+            Value::Object(values) => {
+                let mut m = HashMap::with_capacity(values.len());
+                for (k, v) in values {
+                    m.insert(
+                        json2ch(&Value::String(k.to_owned()), key_type, epoch_unit)?,
+                        json2ch(v, value_type, epoch_unit)?,
+                    );
+                }
+                Ok(CHValue::Map(key_type, value_type, Arc::new(m)))
+            }
+            _ => Err(anyhow!("cannot convert {} to Map", v)),
+        },
+        t => Err(anyhow!("unsupported type {}", t)),
+    }
+}
+
+fn to_i128(v: &Value) -> Result<i128> {
+    let n = match v {
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(x), _) => return Ok(x as i128),
+            (_, Some(x)) => return Ok(x as i128),
+            _ => to_f64(v)?,
+        },
+        Value::String(s) => match s.trim().parse::<i128>() {
+            Ok(x) => return Ok(x),
+            Err(_) => to_f64(v)?,
+        },
+        _ => return Err(anyhow!("cannot convert {} to integer", v)),
+    };
+    if n.fract() != 0.0 {
+        return Err(anyhow!("cannot convert {} to integer", v));
+    }
+    Ok(n as i128)
+}
+
+fn to_f64(v: &Value) -> Result<f64> {
+    match v {
+        Value::Number(n) => n
+            .as_f64()
+            .ok_or_else(|| anyhow!("cannot convert {} to float", v)),
+        Value::String(s) => Ok(s.trim().parse::<f64>()?),
+        _ => Err(anyhow!("cannot convert {} to float", v)),
+    }
+}
+
+/// converts ISO-8601 string or numeric epoch timestamp into nanoseconds since epoch
+fn to_nanos(v: &Value, epoch_unit: i64) -> Result<i64> {
+    let s = match v {
+        Value::Number(n) => return epoch_to_nanos(n.as_i64(), n.as_f64(), epoch_unit, v),
+        Value::String(s) => s.trim(),
+        _ => return Err(anyhow!("cannot convert {} to timestamp", v)),
+    };
+    let nanos = if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        t.timestamp_nanos_opt()
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f") {
+        t.and_utc().timestamp_nanos_opt()
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f") {
+        t.and_utc().timestamp_nanos_opt()
+    } else if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        d.and_hms_opt(0, 0, 0)
+            .and_then(|t| t.and_utc().timestamp_nanos_opt())
+    } else {
+        return epoch_to_nanos(s.parse::<i64>().ok(), s.parse::<f64>().ok(), epoch_unit, v);
+    };
+    nanos.ok_or_else(|| anyhow!("timestamp {} is out of range", v))
+}
+
+fn epoch_to_nanos(int: Option<i64>, float: Option<f64>, epoch_unit: i64, v: &Value) -> Result<i64> {
+    match (int, float) {
+        (Some(x), _) => x
+            .checked_mul(epoch_unit)
+            .ok_or_else(|| anyhow!("timestamp {} is out of range", v)),
+        (_, Some(x)) => Ok((x * epoch_unit as f64) as i64),
+        _ => Err(anyhow!("cannot convert {} to timestamp", v)),
+    }
+}
+
+/// converts path into JSON pointer
+fn to_pointer(path: &str) -> String {
+    if path.starts_with('/') {
+        return path.to_owned();
+    }
+    path.split('.')
+        .map(|p| format!("/{}", p.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+pub fn new(settings: Settings) -> Result<Decoder> {
+    let mut columns = Vec::with_capacity(settings.columns.len());
+    for c in settings.columns {
+        let sql_type = parse_type(&c.column_type).with_context(|| format!("column {}", c.name))?;
+        let epoch_unit = match c.epoch_unit.as_deref() {
+            None | Some("s") => 1_000_000_000,
+            Some("ms") => 1_000_000,
+            Some("us") => 1_000,
+            Some("ns") => 1,
+            Some(u) => return Err(anyhow!("column {}: unknown epoch unit {}", c.name, u)),
+        };
+        let default = match c.default {
+            None => None,
+            Some(d) => Some(
+                json2ch(&serde_json::to_value(d)?, sql_type, epoch_unit)
+                    .with_context(|| format!("column {}: invalid default", c.name))?,
+            ),
+        };
+        columns.push(Column {
+            pointer: match &c.path {
+                Some(path) => to_pointer(path),
+                None => format!("/{}", c.name.replace('~', "~0").replace('/', "~1")),
+            },
+            name: c.name,
+            sql_type,
+            default,
+            epoch_unit,
+        });
+    }
+    Ok(Decoder { columns })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const S: i64 = 1_000_000_000;
+
+    fn convert(v: Value, t: &str) -> Result<CHValue> {
+        json2ch(&v, parse_type(t)?, S)
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(convert(json!(42), "UInt8").unwrap(), CHValue::UInt8(42));
+        assert_eq!(convert(json!("-42"), "Int64").unwrap(), CHValue::Int64(-42));
+        assert_eq!(convert(json!(42.0), "Int32").unwrap(), CHValue::Int32(42));
+        assert_eq!(
+            convert(json!(u64::MAX), "UInt64").unwrap(),
+            CHValue::UInt64(u64::MAX)
+        );
+        assert_eq!(
+            convert(json!("1.5"), "Float64").unwrap(),
+            CHValue::Float64(1.5)
+        );
+        assert!(convert(json!(256), "UInt8").is_err());
+        assert!(convert(json!(-1), "UInt64").is_err());
+        assert!(convert(json!(1.5), "Int32").is_err());
+        assert!(convert(json!("x"), "Int32").is_err());
+        assert!(convert(json!([1]), "Float64").is_err());
+    }
+
+    #[test]
+    fn bools_and_strings() {
+        assert_eq!(convert(json!("1"), "Bool").unwrap(), CHValue::Bool(true));
+        assert_eq!(convert(json!(0), "Bool").unwrap(), CHValue::Bool(false));
+        assert!(convert(json!("yes"), "Bool").is_err());
+        assert_eq!(convert(json!("a"), "String").unwrap(), CHValue::from("a"));
+        assert_eq!(
+            convert(json!({"a": 1}), "String").unwrap(),
+            CHValue::from(r#"{"a":1}"#)
+        );
+    }
+
+    #[test]
+    fn nullable() {
+        let t = parse_type("Nullable(Int32)").unwrap();
+        let SqlType::Nullable(inner) = t else {
+            unreachable!()
+        };
+        assert_eq!(
+            json2ch(&json!(null), t, S).unwrap(),
+            CHValue::Nullable(Either::Left(inner))
+        );
+        assert_eq!(
+            json2ch(&json!(7), t, S).unwrap(),
+            CHValue::Nullable(Either::Right(Box::new(CHValue::Int32(7))))
+        );
+        assert!(convert(json!(null), "Int32").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            convert(json!("1970-01-11"), "Date").unwrap(),
+            CHValue::Date(10)
+        );
+        assert_eq!(convert(json!(86400), "Date").unwrap(), CHValue::Date(1));
+        assert!(convert(json!(-86400), "Date").is_err());
+        assert_eq!(
+            convert(json!("1970-01-01T00:01:00Z"), "DateTime").unwrap(),
+            CHValue::DateTime(60, chrono_tz::UTC)
+        );
+        assert_eq!(
+            convert(json!(1.5), "DateTime64(3)").unwrap(),
+            CHValue::DateTime64(1500, (3, chrono_tz::UTC))
+        );
+    }
+
+    #[test]
+    fn arrays_and_maps() {
+        let t = parse_type("Array(UInt8)").unwrap();
+        let SqlType::Array(inner) = t else {
+            unreachable!()
+        };
+        assert_eq!(
+            json2ch(&json!([1, "2"]), t, S).unwrap(),
+            CHValue::Array(inner, Arc::new(vec![CHValue::UInt8(1), CHValue::UInt8(2)]))
+        );
+        assert!(convert(json!([1, -2]), "Array(UInt8)").is_err());
+        assert!(convert(json!({}), "Array(UInt8)").is_err());
+
+        // maps are not comparable as values
+        let CHValue::Map(_, _, m) = convert(json!({"a": 1}), "Map(String, Int64)").unwrap() else {
+            panic!("expected map")
+        };
+        assert_eq!(m.len(), 1);
+        assert_eq!(m[&CHValue::from("a")], CHValue::Int64(1));
+        assert!(convert(json!([]), "Map(String, Int64)").is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(to_nanos(&json!(2), S).unwrap(), 2 * S);
+        assert_eq!(to_nanos(&json!(1500), 1_000_000).unwrap(), 1_500_000_000);
+        assert_eq!(to_nanos(&json!(0.25), S).unwrap(), 250_000_000);
+        assert_eq!(to_nanos(&json!(" 3 "), S).unwrap(), 3 * S);
+        assert_eq!(
+            to_nanos(&json!("1970-01-01T00:00:01.5+00:00"), S).unwrap(),
+            1_500_000_000
+        );
+        assert_eq!(to_nanos(&json!("1970-01-01T01:00:00+01:00"), S).unwrap(), 0);
+        assert_eq!(
+            to_nanos(&json!("1970-01-01 00:00:02.25"), S).unwrap(),
+            2_250_000_000
+        );
+        assert_eq!(to_nanos(&json!("1970-01-02"), S).unwrap(), 86400 * S);
+        assert_eq!(to_nanos(&json!(-1), S).unwrap(), -S);
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        assert!(to_nanos(&json!(i64::MAX), S).is_err());
+        assert!(to_nanos(&json!("3000-01-01"), S).is_err());
+        assert!(to_nanos(&json!("yesterday"), S).is_err());
+        assert!(to_nanos(&json!(true), S).is_err());
+        assert!(to_nanos(&json!(null), S).is_err());
+    }
+}
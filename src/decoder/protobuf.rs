@@ -0,0 +1,476 @@
+//! generic protobuf message decoder.
+//!
+//! Takes schema either from configured `.proto` file, compiled descriptor set,
+//! or from [Schema Registry] (see [registry] for subject and client settings),
+//! and expects messages to have Confluent-compatible [header],
+//! including the array of message indexes.
+//!
+//! Supports all scalar types, enums (ingested as strings), repeated fields and maps
+//! of those, as well as well-known `Timestamp` (as `DateTime64(9)`), `Duration`
+//! (as `Int64` nanoseconds) and wrapper types (as the wrapped type).
+//! Other nested messages are not supported. Schemas fetched from the registry
+//! may only import well-known types.
+//!
+//! [Schema Registry]: https://docs.confluent.io/platform/current/schema-registry/index.html
+//! [header]: https://docs.confluent.io/platform/current/schema-registry/fundamentals/serdes-develop/index.html#wire-format
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use clickhouse_rs::types::{DateTimeType, SqlType, Value as CHValue};
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, FileDescriptor, Kind, MapKey,
+    MessageDescriptor, Value,
+};
+use protox::{
+    file::{ChainFileResolver, File, FileResolver, GoogleFileResolver},
+    Compiler,
+};
+use schema_registry_api::SchemaType;
+use serde::Deserialize;
+
+use super::{registry, CONFLUENT_HEADER_LEN};
+
+/// name under which schema fetched from registry is compiled
+const REGISTRY_FILE_NAME: &str = "registry.proto";
+
+#[derive(Deserialize)]
+pub struct Settings {
+    pub field_names: Option<HashMap<String, String>>,
+    pub include_fields: Option<Vec<String>>,
+    pub exclude_fields: Option<Vec<String>>,
+    /// `.proto` file with message definition
+    pub proto_file: Option<String>,
+    /// directories to look up imports of `proto_file` in (default: directory of `proto_file`)
+    pub include_paths: Option<Vec<String>>,
+    /// binary-encoded `FileDescriptorSet`, as produced by `protoc --descriptor_set_out`
+    pub descriptor_set: Option<String>,
+    #[serde(flatten)]
+    pub registry: registry::Settings,
+    /// fully qualified name of message (default: first message of `.proto` file or registry schema)
+    pub message_type: Option<String>,
+}
+
+pub struct Decoder {
+    message: MessageDescriptor,
+    file: Option<FileDescriptor>,
+    columns: Vec<Column>,
+}
+
+/// field of message ingested into CH column
+struct Column {
+    name: String,
+    field: FieldDescriptor,
+    kind: ColumnKind,
+}
+
+enum ColumnKind {
+    Scalar,
+    Array(&'static SqlType),
+    Map(&'static SqlType, &'static SqlType),
+}
+
+impl super::Decoder for Decoder {
+    fn get_name(&self) -> String {
+        String::from("protobuf")
+    }
+
+    fn columns(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    fn decode(&self, message: &[u8]) -> Result<Vec<CHValue>> {
+        if message.len() < CONFLUENT_HEADER_LEN {
+            return Err(anyhow!("message is too short: {} bytes", message.len()));
+        }
+        if message[0] != 0 {
+            return Err(anyhow!("unknown magic byte {}", message[0]));
+        }
+        let mut buf = &message[CONFLUENT_HEADER_LEN..];
+        let indexes = read_message_indexes(&mut buf)?;
+        if let Some(file) = &self.file {
+            let desc = resolve_message(file, &indexes)?;
+            if desc != self.message {
+                return Err(anyhow!(
+                    "unexpected message type {}, expecting {}",
+                    desc.full_name(),
+                    self.message.full_name()
+                ));
+            }
+        }
+        let msg = DynamicMessage::decode(self.message.clone(), buf)?;
+        let mut row = Vec::with_capacity(self.columns.len());
+        for column in &self.columns {
+            let value = msg.get_field(&column.field);
+            let v = match (&column.kind, value.as_ref()) {
+                (ColumnKind::Scalar, v) => proto2ch(v, &column.field.kind())?,
+                (ColumnKind::Array(t), Value::List(values)) => {
+                    let kind = column.field.kind();
+                    let mut arr = Vec::with_capacity(values.len());
+                    for v in values {
+                        arr.push(proto2ch(v, &kind)?);
+                    }
+                    CHValue::Array(t, Arc::new(arr))
+                }
+                (ColumnKind::Map(kt, vt), Value::Map(values)) => {
+                    let value_kind = match column.field.kind() {
+                        Kind::Message(entry) => entry.map_entry_value_field().kind(),
+                        k => return Err(anyhow!("unexpected map entry kind {:?}", k)),
+                    };
+                    let mut m = HashMap::with_capacity(values.len());
+                    for (k, v) in values {
+                        m.insert(map_key2ch(k), proto2ch(v, &value_kind)?);
+                    }
+                    CHValue::Map(kt, vt, Arc::new(m))
+                }
+                (_, v) => {
+                    return Err(anyhow!(
+                        "field {}: unexpected value {:?}",
+                        column.field.name(),
+                        v
+                    ))
+                }
+            };
+            row.push(v);
+        }
+        Ok(row)
+    }
+}
+
+/// converts single (non-repeated) protobuf value into CH value
+fn proto2ch(v: &Value, kind: &Kind) -> Result<CHValue> {
+    match (v, kind) {
+        (Value::Bool(x), _) => Ok(CHValue::from(*x)),
+        (Value::I32(x), _) => Ok(CHValue::from(*x)),
+        (Value::I64(x), _) => Ok(CHValue::from(*x)),
+        (Value::U32(x), _) => Ok(CHValue::from(*x)),
+        (Value::U64(x), _) => Ok(CHValue::from(*x)),
+        (Value::F32(x), _) => Ok(CHValue::from(*x)),
+        (Value::F64(x), _) => Ok(CHValue::from(*x)),
+        (Value::String(x), _) => Ok(CHValue::from(x.as_str())),
+        (Value::Bytes(x), _) => Ok(CHValue::from(x.to_vec())),
+        (Value::EnumNumber(x), Kind::Enum(e)) => match e.get_value(*x) {
+            Some(v) => Ok(CHValue::from(v.name())),
+            None => Ok(CHValue::from(x.to_string())),
+        },
+        (Value::Message(m), Kind::Message(desc)) => match desc.full_name() {
+            "google.protobuf.Timestamp" => {
+                Ok(CHValue::DateTime64(to_nanos(m)?, (9, chrono_tz::UTC)))
+            }
+            "google.protobuf.Duration" => Ok(CHValue::from(to_nanos(m)?)),
+            name if is_wrapper(name) => {
+                let field = desc
+                    .get_field(1)
+                    .ok_or_else(|| anyhow!("malformed wrapper type {}", name))?;
+                proto2ch(m.get_field(&field).as_ref(), &field.kind())
+            }
+            name => Err(anyhow!("unsupported nested message {}", name)),
+        },
+        (v, k) => Err(anyhow!("unexpected value {:?} of type {:?}", v, k)),
+    }
+}
+
+fn map_key2ch(k: &MapKey) -> CHValue {
+    match k {
+        MapKey::Bool(x) => CHValue::from(*x),
+        MapKey::I32(x) => CHValue::from(*x),
+        MapKey::I64(x) => CHValue::from(*x),
+        MapKey::U32(x) => CHValue::from(*x),
+        MapKey::U64(x) => CHValue::from(*x),
+        MapKey::String(x) => CHValue::from(x.as_str()),
+    }
+}
+
+/// converts seconds and nanos of `Timestamp` or `Duration` into nanoseconds, failing if they
+/// don't fit into 64 bits, e.g. for timestamps after year 2262
+fn to_nanos(m: &DynamicMessage) -> Result<i64> {
+    let seconds = match m.get_field_by_number(1).as_deref() {
+        Some(Value::I64(x)) => *x,
+        _ => 0,
+    };
+    let nanos = match m.get_field_by_number(2).as_deref() {
+        Some(Value::I32(x)) => *x as i64,
+        _ => 0,
+    };
+    seconds
+        .checked_mul(1_000_000_000)
+        .and_then(|s| s.checked_add(nanos))
+        .ok_or_else(|| anyhow!("{}s {}ns is out of range of nanoseconds", seconds, nanos))
+}
+
+fn is_wrapper(name: &str) -> bool {
+    matches!(
+        name,
+        "google.protobuf.DoubleValue"
+            | "google.protobuf.FloatValue"
+            | "google.protobuf.Int64Value"
+            | "google.protobuf.UInt64Value"
+            | "google.protobuf.Int32Value"
+            | "google.protobuf.UInt32Value"
+            | "google.protobuf.BoolValue"
+            | "google.protobuf.StringValue"
+            | "google.protobuf.BytesValue"
+    )
+}
+
+/// translates protobuf type into clickhouse type
+fn get_kind_type(kind: &Kind) -> Result<&'static SqlType> {
+    match kind {
+        Kind::Double => Ok(&SqlType::Float64),
+        Kind::Float => Ok(&SqlType::Float32),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => Ok(&SqlType::Int32),
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => Ok(&SqlType::Int64),
+        Kind::Uint32 | Kind::Fixed32 => Ok(&SqlType::UInt32),
+        Kind::Uint64 | Kind::Fixed64 => Ok(&SqlType::UInt64),
+        Kind::Bool => Ok(&SqlType::Bool),
+        Kind::String | Kind::Bytes | Kind::Enum(_) => Ok(&SqlType::String),
+        Kind::Message(desc) => match desc.full_name() {
+            "google.protobuf.Timestamp" => Ok(&SqlType::DateTime(DateTimeType::DateTime64(
+                9,
+                chrono_tz::UTC,
+            ))),
+            "google.protobuf.Duration" => Ok(&SqlType::Int64),
+            name if is_wrapper(name) => match desc.get_field(1) {
+                Some(f) => get_kind_type(&f.kind()),
+                None => Err(anyhow!("malformed wrapper type {}", name)),
+            },
+            name => Err(anyhow!("nested message {} is not supported", name)),
+        },
+    }
+}
+
+/// reads array of message indexes following the Confluent header,
+/// pointing to message type within schema file
+fn read_message_indexes(buf: &mut &[u8]) -> Result<Vec<usize>> {
+    let count = read_zigzag(buf)?;
+    if count == 0 {
+        // optimization for the most common case of first message in file
+        return Ok(vec![0]);
+    }
+    // each index takes at least one byte
+    if count < 0 || count as usize > buf.len() {
+        return Err(anyhow!("invalid number of message indexes {}", count));
+    }
+    let mut indexes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let idx = read_zigzag(buf)?;
+        if idx < 0 {
+            return Err(anyhow!("invalid message index {}", idx));
+        }
+        indexes.push(idx as usize);
+    }
+    Ok(indexes)
+}
+
+fn read_zigzag(buf: &mut &[u8]) -> Result<i64> {
+    let n = prost::encoding::decode_varint(buf).context("reading message indexes")?;
+    Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+}
+
+fn resolve_message(file: &FileDescriptor, indexes: &[usize]) -> Result<MessageDescriptor> {
+    let not_found = || anyhow!("message with indexes {:?} not found in schema", indexes);
+    let mut desc = file.messages().nth(indexes[0]).ok_or_else(not_found)?;
+    for idx in &indexes[1..] {
+        let child = desc.child_messages().nth(*idx);
+        desc = child.ok_or_else(not_found)?;
+    }
+    Ok(desc)
+}
+
+pub async fn new(topic: &str, settings: Settings) -> Result<Decoder> {
+    let (pool, file) = get_schema(topic, &settings).await?;
+    let message = match (&settings.message_type, &file) {
+        (Some(name), _) => pool
+            .get_message_by_name(name)
+            .ok_or_else(|| anyhow!("message {} not found in schema", name))?,
+        (None, Some(file)) => file
+            .messages()
+            .next()
+            .ok_or_else(|| anyhow!("no messages defined in {}", file.name()))?,
+        (None, None) => return Err(anyhow!("message_type must be specified")),
+    };
+    let name_overrides = settings.field_names.unwrap_or_default();
+    let include_fields = settings.include_fields.unwrap_or_default();
+    let exclude_fields = settings.exclude_fields.unwrap_or_default();
+    let mut columns = Vec::new();
+    for field in message.fields() {
+        let name = field.name();
+        if exclude_fields.iter().any(|c| c == name) {
+            continue;
+        }
+        if !include_fields.is_empty() && !include_fields.iter().any(|c| c == name) {
+            continue;
+        }
+        let kind = if field.is_map() {
+            let entry = match field.kind() {
+                Kind::Message(entry) => entry,
+                k => return Err(anyhow!("field {}: unexpected map kind {:?}", name, k)),
+            };
+            ColumnKind::Map(
+                get_kind_type(&entry.map_entry_key_field().kind())
+                    .with_context(|| format!("field {}", name))?,
+                get_kind_type(&entry.map_entry_value_field().kind())
+                    .with_context(|| format!("field {}", name))?,
+            )
+        } else if field.is_list() {
+            ColumnKind::Array(
+                get_kind_type(&field.kind()).with_context(|| format!("field {}", name))?,
+            )
+        } else {
+            get_kind_type(&field.kind()).with_context(|| format!("field {}", name))?;
+            ColumnKind::Scalar
+        };
+        columns.push(Column {
+            name: name_overrides
+                .get(name)
+                .map_or_else(|| name.to_owned(), |n| n.to_owned()),
+            field,
+            kind,
+        });
+    }
+    Ok(Decoder {
+        message,
+        file,
+        columns,
+    })
+}
+
+/// returns descriptor pool and, if known, the schema file message indexes are resolved against
+async fn get_schema(
+    topic: &str,
+    settings: &Settings,
+) -> Result<(DescriptorPool, Option<FileDescriptor>)> {
+    if let Some(f) = &settings.descriptor_set {
+        let pool = DescriptorPool::decode(fs::read(f)?.as_slice())?;
+        return Ok((pool, None));
+    }
+    let (mut compiler, file_name) = match (&settings.proto_file, settings.registry.client()?) {
+        (Some(f), _) => {
+            let includes = match &settings.include_paths {
+                Some(paths) => paths.clone(),
+                None => vec![Path::new(f)
+                    .parent()
+                    .map_or(String::from("."), |p| p.to_string_lossy().to_string())],
+            };
+            let mut compiler = Compiler::new(includes)?;
+            compiler.open_file(f)?;
+            let name = compiler
+                .files()
+                .find(|m| !m.is_import())
+                .map(|m| m.name().to_owned())
+                .ok_or_else(|| anyhow!("cannot compile {}", f))?;
+            (compiler, name)
+        }
+        (None, Some(client)) => {
+            let subject = client
+                .get_subject(&settings.registry, topic, settings.message_type.as_deref())
+                .await?;
+            if subject.schema_type != SchemaType::Protobuf {
+                return Err(anyhow!(
+                    "subject {} has schema type {:?}, expecting protobuf",
+                    subject.subject,
+                    subject.schema_type
+                ));
+            }
+            let mut resolver = ChainFileResolver::new();
+            resolver.add(SourceResolver {
+                source: subject.schema,
+            });
+            resolver.add(GoogleFileResolver::new());
+            let mut compiler = Compiler::with_file_resolver(resolver);
+            compiler.open_file(REGISTRY_FILE_NAME)?;
+            (compiler, String::from(REGISTRY_FILE_NAME))
+        }
+        (None, None) => {
+            return Err(anyhow!(
+                "registry_url, proto_file or descriptor_set must be specified"
+            ))
+        }
+    };
+    let pool = compiler.include_imports(true).descriptor_pool();
+    let file = pool.get_file_by_name(&file_name);
+    Ok((pool, file))
+}
+
+/// resolves schema fetched from registry
+struct SourceResolver {
+    source: String,
+}
+
+impl FileResolver for SourceResolver {
+    fn open_file(&self, name: &str) -> Result<File, protox::Error> {
+        if name == REGISTRY_FILE_NAME {
+            File::from_source(name, &self.source)
+        } else {
+            Err(protox::Error::file_not_found(name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_reflect::ReflectMessage;
+
+    use crate::decoder::Decoder as _;
+
+    use super::*;
+
+    fn timestamp(seconds: i64, nanos: i32) -> DynamicMessage {
+        let desc = DescriptorPool::global()
+            .get_message_by_name("google.protobuf.Timestamp")
+            .unwrap();
+        let mut m = DynamicMessage::new(desc);
+        m.set_field_by_number(1, Value::I64(seconds));
+        m.set_field_by_number(2, Value::I32(nanos));
+        m
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(to_nanos(&timestamp(0, 0)).unwrap(), 0);
+        assert_eq!(to_nanos(&timestamp(2, 5)).unwrap(), 2_000_000_005);
+        assert_eq!(to_nanos(&timestamp(-1, 5)).unwrap(), -999_999_995);
+        // 9999-12-31 is valid timestamp, but doesn't fit into 64 bits of nanoseconds
+        assert!(to_nanos(&timestamp(253402300799, 0)).is_err());
+        assert!(to_nanos(&timestamp(i64::MAX / 1_000_000_000, 999_999_999)).is_err());
+        let kind = Kind::Message(timestamp(0, 0).descriptor());
+        assert!(proto2ch(&Value::Message(timestamp(i64::MIN, 0)), &kind).is_err());
+    }
+
+    #[test]
+    fn magic_byte() {
+        let decoder = Decoder {
+            message: timestamp(0, 0).descriptor(),
+            file: None,
+            columns: Vec::new(),
+        };
+        assert!(decoder.decode(&[0, 0, 0, 0, 1, 0]).is_ok());
+        assert!(decoder.decode(&[1, 0, 0, 0, 1, 0]).is_err());
+        assert!(decoder.decode(&[0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn message_indexes() {
+        let mut buf: &[u8] = &[0x00, 0xff];
+        assert_eq!(read_message_indexes(&mut buf).unwrap(), vec![0]);
+        assert_eq!(buf, &[0xff]);
+
+        // two indexes: 1 and 3
+        let mut buf: &[u8] = &[0x04, 0x02, 0x06];
+        assert_eq!(read_message_indexes(&mut buf).unwrap(), vec![1, 3]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn invalid_message_indexes() {
+        // negative count
+        assert!(read_message_indexes(&mut &[0x01][..]).is_err());
+        // count beyond payload
+        assert!(read_message_indexes(&mut &[0xfe, 0xff, 0xff, 0xff, 0x0f][..]).is_err());
+        assert!(read_message_indexes(&mut &[0x04, 0x02][..]).is_err());
+        // negative index
+        assert!(read_message_indexes(&mut &[0x02, 0x01][..]).is_err());
+        // truncated varint
+        assert!(read_message_indexes(&mut &[0x80][..]).is_err());
+        assert!(read_message_indexes(&mut &[][..]).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! [Schema Registry] settings and lookups shared by generic decoders.
+//!
+//! Subject of the schema is derived from the topic according to `subject_name_strategy`,
+//! mirroring Confluent serializers:
+//! - `topic` (default): `<topic>-value`,
+//! - `record`: fully qualified `record_name`,
+//! - `topic-record`: `<topic>-<record_name>`,
+//!
+//! or is set explicitly with `subject`. Latest version of the subject is used,
+//! unless `schema_version` is pinned.
+//!
+//! Registry client supports basic (`registry_username`, `registry_password`) and bearer
+//! (`registry_token`) authentication, custom CA bundle and client certificate for mutual TLS.
+//! Failed requests are retried with exponential backoff, and if `registry_cache_dir` is set,
+//! fetched schemas are stored there and used when registry is still unavailable after retries.
+//! Otherwise ingester fails, leaving messages which need the schema uncommitted.
+//!
+//! [Schema Registry]: https://docs.confluent.io/platform/current/schema-registry/index.html
+use std::{fs, future::Future, num::NonZeroU32, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION},
+    Certificate, Client, Identity, Url,
+};
+use schema_registry_api::{
+    Schema, SchemaId, SchemaRegistry, SchemaRegistryError, SchemaVersion, Subject, SubjectName,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::Unavailable;
+
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct Settings {
+    pub registry_url: Option<String>,
+    /// "topic", "record" or "topic-record" (default: "topic")
+    pub subject_name_strategy: Option<String>,
+    /// explicit subject name, overrides `subject_name_strategy`
+    pub subject: Option<String>,
+    /// fully qualified record name for "record" and "topic-record" strategies
+    pub record_name: Option<String>,
+    /// version of subject to use (default: latest)
+    pub schema_version: Option<u32>,
+    pub registry_username: Option<String>,
+    pub registry_password: Option<String>,
+    /// bearer token, alternative to username and password
+    pub registry_token: Option<String>,
+    /// PEM bundle of CA certificates to verify registry with (default: system certificates)
+    pub registry_ca_file: Option<String>,
+    /// PEM client certificate for mutual TLS, requires `registry_key_file`
+    pub registry_cert_file: Option<String>,
+    /// PEM PKCS#8 private key of client certificate
+    pub registry_key_file: Option<String>,
+    /// timeout of registry requests (default: 30)
+    pub registry_timeout_seconds: Option<u64>,
+    /// number of retries of failed registry requests (default: 5)
+    pub registry_retries: Option<u32>,
+    /// directory to cache fetched schemas in
+    pub registry_cache_dir: Option<String>,
+}
+
+/// Schema Registry client, retrying failed requests and caching fetched schemas on disk
+pub struct Registry {
+    client: SchemaRegistry,
+    retries: u32,
+    cache_dir: Option<PathBuf>,
+}
+
+impl Settings {
+    /// returns registry client, if registry is configured
+    pub fn client(&self) -> Result<Option<Registry>> {
+        let url = match &self.registry_url {
+            None => return Ok(None),
+            Some(url) => Url::parse(url)?,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("application/vnd.schemaregistry.v1+json"),
+        );
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(
+                self.registry_timeout_seconds.unwrap_or(30),
+            ))
+            .connect_timeout(Duration::from_secs(10));
+        if let Some(f) = &self.registry_ca_file {
+            let pem = fs::read(f).with_context(|| format!("reading {}", f))?;
+            for cert in Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        match (&self.registry_cert_file, &self.registry_key_file) {
+            (None, None) => (),
+            (Some(cert), Some(key)) => {
+                let cert = fs::read(cert).with_context(|| format!("reading {}", cert))?;
+                let key = fs::read(key).with_context(|| format!("reading {}", key))?;
+                builder = builder.identity(Identity::from_pkcs8_pem(&cert, &key)?);
+            }
+            _ => {
+                return Err(anyhow!(
+                    "registry_cert_file and registry_key_file must be specified together"
+                ))
+            }
+        }
+        let auth = match (
+            &self.registry_username,
+            &self.registry_password,
+            &self.registry_token,
+        ) {
+            (None, None, None) => None,
+            (Some(user), password, None) => {
+                let credentials = format!("{}:{}", user, password.as_deref().unwrap_or(""));
+                Some(format!("Basic {}", BASE64_STANDARD.encode(credentials)))
+            }
+            (None, None, Some(token)) => Some(format!("Bearer {}", token)),
+            _ => {
+                return Err(anyhow!(
+                    "either registry_username or registry_token must be specified"
+                ))
+            }
+        };
+        if let Some(auth) = auth {
+            let mut value = HeaderValue::from_str(&auth)?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = builder.default_headers(headers).build()?;
+        Ok(Some(Registry {
+            client: SchemaRegistry::new(
+                url,
+                reqwest_middleware::ClientBuilder::new(client).build(),
+            ),
+            retries: self.registry_retries.unwrap_or(5),
+            cache_dir: self.registry_cache_dir.as_ref().map(PathBuf::from),
+        }))
+    }
+
+    /// returns subject name of topic schema, `record_name` defaults to `default_record`
+    pub fn subject_name(&self, topic: &str, default_record: Option<&str>) -> Result<SubjectName> {
+        let record = || {
+            self.record_name
+                .as_deref()
+                .or(default_record)
+                .ok_or_else(|| anyhow!("record_name must be specified for record name strategies"))
+        };
+        let name = match (&self.subject, self.subject_name_strategy.as_deref()) {
+            (Some(subject), _) => subject.to_owned(),
+            (None, None) | (None, Some("topic")) => format!("{topic}-value"),
+            (None, Some("record")) => record()?.to_owned(),
+            (None, Some("topic-record")) => format!("{topic}-{}", record()?),
+            (None, Some(s)) => return Err(anyhow!("unknown subject name strategy {}", s)),
+        };
+        Ok(name.parse::<SubjectName>()?)
+    }
+
+    pub fn version(&self) -> Result<SchemaVersion> {
+        match self.schema_version {
+            None => Ok(SchemaVersion::Latest),
+            Some(v) => Ok(SchemaVersion::Version(
+                NonZeroU32::new(v).ok_or_else(|| anyhow!("schema_version must be positive"))?,
+            )),
+        }
+    }
+}
+
+impl Registry {
+    /// fetches configured version of topic's subject
+    pub async fn get_subject(
+        &self,
+        settings: &Settings,
+        topic: &str,
+        default_record: Option<&str>,
+    ) -> Result<Subject> {
+        let subject_name = settings.subject_name(topic, default_record)?;
+        let version = settings.version()?;
+        let cache_name = format!("subject-{}-{}.json", subject_name, version);
+        self.fetch(&cache_name, || async {
+            self.client.subject().version(&subject_name, version).await
+        })
+        .await?
+        .ok_or_else(|| anyhow!("subject {} version {} not found", subject_name, version))
+    }
+
+    /// fetches schema by its ID
+    pub async fn get_schema(&self, id: SchemaId) -> Result<Schema> {
+        let cache_name = format!("schema-{}.json", id);
+        self.fetch(&cache_name, || async {
+            self.client.schema().get(id, None).await
+        })
+        .await?
+        .ok_or_else(|| anyhow!("schema {} not found", id))
+    }
+
+    /// runs registry request with retries, falling back to cached response if it keeps failing
+    async fn fetch<T, F, Fut>(&self, cache_name: &str, request: F) -> Result<Option<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Option<T>, SchemaRegistryError>>,
+    {
+        let cache_file = self.cache_dir.as_ref().map(|d| d.join(cache_name));
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 0;
+        let err = loop {
+            match request().await {
+                Ok(v) => {
+                    if let (Some(v), Some(f)) = (&v, &cache_file) {
+                        if let Err(e) = fs::write(f, serde_json::to_vec(v)?) {
+                            eprintln!("failed to cache schema in {}: {}", f.display(), e);
+                        }
+                    }
+                    return Ok(v);
+                }
+                Err(e) if attempt < self.retries => {
+                    eprintln!("schema registry request failed, retrying: {}", e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                    attempt += 1;
+                }
+                Err(e) => break e,
+            }
+        };
+        match &cache_file {
+            Some(f) if f.exists() => {
+                eprintln!(
+                    "schema registry is unavailable, using cached {}: {}",
+                    f.display(),
+                    err
+                );
+                Ok(Some(serde_json::from_slice(&fs::read(f)?)?))
+            }
+            _ => Err(anyhow::Error::new(err).context(Unavailable("schema registry"))),
+        }
+    }
+}
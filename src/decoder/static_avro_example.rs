@@ -1,11 +1,12 @@
 //! example implementation of statically-typed avro decoder
 use std::io::BufReader;
 
+use anyhow::anyhow;
 use apache_avro::{from_avro_datum, from_value, Schema};
 use clickhouse_rs::types::Value;
 use serde::Deserialize;
 
-use super::{Row, CONFLUENT_HEADER_LEN};
+use super::CONFLUENT_HEADER_LEN;
 
 pub struct Decoder {
     schema: Schema,
@@ -40,14 +41,16 @@ impl super::Decoder for Decoder {
     fn get_name(&self) -> String {
         String::from("static-avro-example")
     }
-    fn decode(&self, message: &[u8]) -> Result<Row, anyhow::Error> {
-        let mut datum = BufReader::new(&message[CONFLUENT_HEADER_LEN..]);
+    fn columns(&self) -> Vec<String> {
+        vec![String::from("a"), String::from("b"), String::from("c")]
+    }
+    fn decode(&self, message: &[u8]) -> Result<Vec<Value>, anyhow::Error> {
+        let body = message
+            .get(CONFLUENT_HEADER_LEN..)
+            .ok_or_else(|| anyhow!("message is too short: {} bytes", message.len()))?;
+        let mut datum = BufReader::new(body);
         let v = from_avro_datum(&self.schema, &mut datum, None)?;
         let r: Entry = from_value::<Entry>(&v)?;
-        Ok(vec![
-            (String::from("a"), Value::from(r.a)),
-            (String::from("b"), Value::from(r.b)),
-            (String::from("c"), Value::from(r.c)),
-        ])
+        Ok(vec![Value::from(r.a), Value::from(r.b), Value::from(r.c)])
     }
 }
@@ -0,0 +1,107 @@
+//! ClickHouse column types used by configurable decoders
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use chrono_tz::Tz;
+use clickhouse_rs::types::{DateTimeType, Decimal, SqlType, Value};
+use either::Either;
+use uuid::Uuid;
+
+/// parses ClickHouse type name, like `Array(Nullable(DateTime64(3, 'UTC')))`.
+///
+/// Timezones of `DateTime` columns are accepted but ignored,
+/// since values are always passed as UTC timestamps.
+pub fn parse_type(name: &str) -> Result<&'static SqlType> {
+    let name = name.trim();
+    let (base, args) = match name.find('(') {
+        None => (name, Vec::new()),
+        Some(i) => {
+            if !name.ends_with(')') {
+                return Err(anyhow!("malformed type {}", name));
+            }
+            (name[..i].trim(), split_args(&name[i + 1..name.len() - 1]))
+        }
+    };
+    let t = match (base, args.as_slice()) {
+        ("Bool", []) => SqlType::Bool,
+        ("UInt8", []) => SqlType::UInt8,
+        ("UInt16", []) => SqlType::UInt16,
+        ("UInt32", []) => SqlType::UInt32,
+        ("UInt64", []) => SqlType::UInt64,
+        ("Int8", []) => SqlType::Int8,
+        ("Int16", []) => SqlType::Int16,
+        ("Int32", []) => SqlType::Int32,
+        ("Int64", []) => SqlType::Int64,
+        ("Float32", []) => SqlType::Float32,
+        ("Float64", []) => SqlType::Float64,
+        ("String", []) => SqlType::String,
+        ("UUID", []) => SqlType::Uuid,
+        ("Date", []) => SqlType::Date,
+        ("DateTime", []) | ("DateTime", [_]) => SqlType::DateTime(DateTimeType::DateTime32),
+        ("DateTime64", [precision]) | ("DateTime64", [precision, _]) => {
+            let p = precision
+                .parse::<u32>()
+                .map_err(|_| anyhow!("invalid DateTime64 precision {}", precision))?;
+            if p > 9 {
+                return Err(anyhow!("invalid DateTime64 precision {}", p));
+            }
+            SqlType::DateTime(DateTimeType::DateTime64(p, Tz::UTC))
+        }
+        ("Nullable", [t]) => SqlType::Nullable(parse_type(t)?),
+        ("Array", [t]) => SqlType::Array(parse_type(t)?),
+        ("Map", [k, v]) => SqlType::Map(parse_type(k)?, parse_type(v)?),
+        _ => return Err(anyhow!("unsupported type {}", name)),
+    };
+    Ok(t.into())
+}
+
+/// splits type arguments by commas that are not nested into parentheses or quotes
+fn split_args(s: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                args.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    args.push(s[start..].trim());
+    args
+}
+
+/// returns zero value of type: 0, empty string, epoch etc, and NULL for nullable types
+pub fn zero_value(t: &'static SqlType) -> Value {
+    match t {
+        SqlType::Bool => Value::from(false),
+        SqlType::UInt8 => Value::UInt8(0),
+        SqlType::UInt16 => Value::UInt16(0),
+        SqlType::UInt32 => Value::UInt32(0),
+        SqlType::UInt64 => Value::UInt64(0),
+        SqlType::Int8 => Value::Int8(0),
+        SqlType::Int16 => Value::Int16(0),
+        SqlType::Int32 => Value::Int32(0),
+        SqlType::Int64 => Value::Int64(0),
+        SqlType::Float32 => Value::Float32(0.0),
+        SqlType::Float64 => Value::Float64(0.0),
+        SqlType::Uuid => Value::from(Uuid::nil()),
+        SqlType::Date => Value::Date(0),
+        SqlType::DateTime(DateTimeType::DateTime64(precision, tz)) => {
+            Value::DateTime64(0, (*precision, *tz))
+        }
+        SqlType::DateTime(_) => Value::DateTime(0, Tz::UTC),
+        SqlType::Nullable(t) => Value::Nullable(Either::Left(t)),
+        SqlType::Array(t) => Value::Array(t, Arc::new(Vec::new())),
+        SqlType::Map(k, v) => Value::Map(k, v, Arc::new(HashMap::new())),
+        SqlType::Decimal(_, scale) => Value::Decimal(Decimal::new(0, *scale)),
+        SqlType::FixedString(n) => Value::String(Arc::new(vec![0u8; *n])),
+        _ => Value::from(String::new()),
+    }
+}
@@ -1,73 +1,360 @@
 //! Consumes messages from Kafka, and inserts decoded rows to CH
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    env, fs, iter,
+    str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
-use clickhouse_rs::{Block, Pool};
+use clickhouse_rs::{
+    types::{Options, Value},
+    Block, Pool,
+};
 use rdkafka::{
-    consumer::{Consumer, StreamConsumer},
+    consumer::{Consumer, RebalanceProtocol, StreamConsumer},
+    message::OwnedMessage,
     ClientConfig, Message, Offset, TopicPartitionList,
 };
-use tokio::time::sleep;
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinSet},
+    time::sleep,
+};
+use url::Url;
 
 use crate::{
-    decoder::{self, Decoder, Row},
+    context::IngesterContext,
+    dead_letter::DeadLetterQueue,
+    decoder::{Decoder, Row, TopicDecoders, Unavailable},
+    inserter::{Inserter, TableBatch},
+    metadata::MetadataColumns,
+    metrics::Metrics,
+    retry::Retry,
+    routing::Router,
     settings,
 };
 
 const CH_BACKOFF: std::time::Duration = Duration::from_secs(5);
+const KAFKA_TIMEOUT: Duration = Duration::from_secs(10);
+/// max number of messages decoded at once by each decoding task
+const DECODE_CHUNK: usize = 64;
+
+/// how messages without payload are handled
+enum Tombstones {
+    Skip,
+    DeadLetter,
+    /// insert row of given columns: tombstone column set to 1, followed by metadata columns
+    /// identifying deleted row
+    Delete(Arc<[String]>),
+}
+
+/// batches of single partition in exactly-once mode, whose rows are not mixed with other
+/// partitions, so that batches are reproduced from the same messages after restart
+struct PartitionBatches {
+    /// indexes of partition's batch of each table in ingester's batches
+    batches: Vec<usize>,
+    /// offset of first pending message
+    start: Option<i64>,
+    /// number of pending messages, including ones without rows
+    messages: usize,
+    /// end offset of batch, which was about to be inserted before restart
+    boundary: Option<i64>,
+    /// previous batches are being inserted, next ones wait until they are committed
+    flushing: bool,
+}
+
+impl PartitionBatches {
+    /// adds batches of each table to ingester's batches
+    fn new(batches: &mut Vec<TableBatch>, tables: &[String]) -> Self {
+        let start = batches.len();
+        batches.extend(tables.iter().map(|t| TableBatch::new(t)));
+        PartitionBatches {
+            batches: (start..batches.len()).collect(),
+            start: None,
+            messages: 0,
+            boundary: None,
+            flushing: false,
+        }
+    }
+
+    /// accounts message with given offset, `boundary` is committed offset and end offset of batch,
+    /// which are looked up for first message after assignment
+    fn track(&mut self, offset: i64, boundary: Option<(i64, i64)>) {
+        // consuming from before committed offset, e.g. from offset stored in ClickHouse,
+        // when committing it to Kafka failed
+        if let Some((_, end)) = boundary.filter(|(start, _)| offset >= *start) {
+            self.boundary = Some(end);
+        }
+        // messages up to boundary are gone, e.g. by retention, so batch can't be reproduced
+        if self.boundary.is_some_and(|b| offset >= b) {
+            self.boundary = None;
+        }
+        self.start.get_or_insert(offset);
+        self.messages += 1;
+    }
+
+    /// returns number of messages that can be added without exceeding batch size, or going
+    /// past boundary recorded before restart, given next offset `end` of partition
+    fn room(&self, end: Option<i64>, batch_size: usize) -> usize {
+        match (self.boundary, end) {
+            // offsets may have gaps, so there are at most that many messages
+            (Some(boundary), Some(end)) => (boundary - end).max(0) as usize,
+            // messages added while flushing are pending until next flush
+            _ if self.flushing => batch_size,
+            _ => batch_size.saturating_sub(self.messages),
+        }
+    }
+
+    /// returns true if batches should be flushed: when they reach boundary recorded before
+    /// restart, or if there is no such boundary, when they are full or batching timed out
+    fn ready(&self, end: i64, batch_size: usize, timed_out: bool) -> bool {
+        self.messages > 0
+            && !self.flushing
+            && match self.boundary {
+                Some(boundary) => end >= boundary,
+                None => timed_out || self.messages >= batch_size,
+            }
+    }
+}
+
+/// batches being inserted, offsets of their partitions are not committed past them until done
+struct Flush {
+    /// offset of first row of each partition
+    first_offsets: HashMap<(Arc<str>, i32), i64>,
+    /// partition and end offset of its batches in exactly-once mode, committed once inserted
+    end: Option<((Arc<str>, i32), i64)>,
+    bytes: usize,
+}
 
 pub struct Ingester {
-    batch: Vec<Row>,
+    /// batches of each table rows are routed to, or of each partition and table in exactly-once mode
+    batches: Vec<TableBatch>,
+    exactly_once: bool,
+    /// batches of each consumed partition in exactly-once mode
+    partitions: HashMap<(Arc<str>, i32), PartitionBatches>,
     batch_size: usize,
     batch_timeout: Duration,
     pool: Pool,
-    consumer: StreamConsumer,
-    decoder: Arc<dyn Decoder + Send + Sync>,
-    table: String,
-    topic: String,
+    inserter: Arc<Inserter>,
+    /// tasks inserting batches, returning ids of their flushes
+    flushes: JoinSet<Result<u64>>,
+    /// batches being inserted by id of their flush
+    in_flight: HashMap<u64, Flush>,
+    next_flush: u64,
+    max_flushes: usize,
+    /// size of payloads of pending messages, after which consuming pauses until flushes are done
+    max_pending_bytes: usize,
+    consumer: StreamConsumer<IngesterContext>,
+    decoders: TopicDecoders,
+    /// columns of rows decoded from each topic, including metadata columns
+    topic_columns: HashMap<String, Arc<[String]>>,
+    /// names of consumed topics, shared by keys of their partitions
+    topic_names: HashSet<Arc<str>>,
+    decode_parallelism: usize,
+    dead_letters: Option<Arc<DeadLetterQueue>>,
+    metadata: MetadataColumns,
+    tombstones: Tombstones,
+    router: Router,
+    /// next offset of each consumed partition
+    consumed: HashMap<(Arc<str>, i32), i64>,
+    /// last committed offset of each partition
+    committed: HashMap<(Arc<str>, i32), i64>,
+    /// table to store offsets in, instead of relying on offsets committed to Kafka
+    offsets_table: Option<String>,
+    consumer_group: String,
+    /// topics and patterns to subscribe to
+    topics: Vec<String>,
+    metrics: Arc<Metrics>,
 }
 
 impl Ingester {
-    pub async fn new(cfg: settings::Ingester) -> Result<Self> {
-        let decoder = decoder::get_decoder(&cfg.decoder, cfg.custom, &cfg.topic)
-            .await
-            .with_context(|| format!("loading decoder"))?;
-        let consumer: StreamConsumer = ClientConfig::new()
+    pub async fn new(name: &str, cfg: settings::Ingester) -> Result<Self> {
+        let metrics = Arc::new(Metrics::new(name));
+        let mut topics: Vec<String> = cfg
+            .topic
+            .into_iter()
+            .chain(cfg.topics.unwrap_or_default())
+            .collect();
+        let mut decoders = TopicDecoders::new(cfg.decoder, cfg.custom);
+        for topic in &topics {
+            decoders.get(topic).await?;
+        }
+        match cfg.topic_pattern {
+            // librdkafka treats subscriptions starting with ^ as regex
+            Some(p) if p.starts_with('^') => topics.push(p),
+            Some(p) => topics.push(format!("^{}", p)),
+            None => (),
+        }
+        if topics.is_empty() {
+            return Err(anyhow!("topic, topics or topic_pattern must be specified"));
+        }
+        let kafka = cfg.kafka.unwrap_or_default();
+        if let Some(v) = kafka.get("enable.auto.commit") {
+            if v != "false" {
+                return Err(anyhow!(
+                    "enable.auto.commit must be false, offsets are committed after inserts"
+                ));
+            }
+        }
+        // consumer group also identifies offsets stored in `offsets_table`
+        if kafka.contains_key("group.id") {
+            return Err(anyhow!("group.id must be set by consumer_group"));
+        }
+        let dead_letters = match cfg.dead_letter_topic {
+            None => None,
+            Some(topic) => {
+                let security = kafka
+                    .iter()
+                    .filter(|(k, _)| {
+                        k.starts_with("security.")
+                            || k.starts_with("sasl.")
+                            || k.starts_with("ssl.")
+                    })
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect();
+                Some(Arc::new(DeadLetterQueue::new(
+                    &cfg.kafka_broker,
+                    topic,
+                    &security,
+                )?))
+            }
+        };
+        let metadata = MetadataColumns::new(cfg.metadata_columns.unwrap_or_default())
+            .context("loading metadata columns")?;
+        let tombstones = match cfg.tombstones.as_deref() {
+            None | Some("skip") => Tombstones::Skip,
+            Some("dead-letter") if dead_letters.is_some() => Tombstones::DeadLetter,
+            Some("dead-letter") => {
+                return Err(anyhow!(
+                    "dead_letter_topic is required to send tombstones to"
+                ))
+            }
+            Some("delete") if !metadata.is_empty() => {
+                let column = cfg
+                    .tombstone_column
+                    .unwrap_or_else(|| String::from("is_deleted"));
+                Tombstones::Delete(
+                    iter::once(column)
+                        .chain(metadata.names().cloned())
+                        .collect(),
+                )
+            }
+            Some("delete") => {
+                return Err(anyhow!(
+                    "metadata_columns are required to identify deleted rows"
+                ))
+            }
+            Some(p) => return Err(anyhow!("unknown tombstones policy {}", p)),
+        };
+        let mut consumer_cfg = ClientConfig::new();
+        consumer_cfg
             .set("bootstrap.servers", cfg.kafka_broker)
             .set("session.timeout.ms", "6000")
             .set("enable.auto.commit", "false")
             .set("auto.offset.reset", "earliest")
-            .set("group.id", cfg.consumer_group.unwrap())
-            .create()
+            .set("group.id", cfg.consumer_group.as_deref().unwrap())
+            .set("statistics.interval.ms", "10000");
+        for (k, v) in kafka {
+            consumer_cfg.set(k, v);
+        }
+        let consumer: StreamConsumer<IngesterContext> = consumer_cfg
+            .create_with_context(IngesterContext::new(
+                metrics.clone(),
+                cfg.offsets_table.is_some(),
+            ))
             .context("creating kafka consumer")?;
-        let pool = Pool::new(cfg.clickhouse_url);
+        let router = Router::new(cfg.clickhouse_table, cfg.routes.unwrap_or_default())
+            .context("loading routes")?;
+        let exactly_once = cfg.exactly_once.unwrap_or(false);
+        // in exactly-once mode batches are added for each consumed partition
+        let batches = match exactly_once {
+            true => Vec::new(),
+            false => router.tables().iter().map(|t| TableBatch::new(t)).collect(),
+        };
+        let retry = Retry::new(cfg.clickhouse.as_ref());
+        let (ch_options, ch_hosts) = clickhouse_options(&cfg.clickhouse_url, cfg.clickhouse)
+            .context("loading clickhouse settings")?;
+        let pool = Pool::new(ch_options);
+        let inserter = Inserter::new(
+            pool.clone(),
+            ch_hosts,
+            retry,
+            dead_letters.clone(),
+            metrics.clone(),
+        );
+        let decode_parallelism = cfg.decode_parallelism.unwrap_or(1);
+        if decode_parallelism == 0 {
+            return Err(anyhow!("decode_parallelism must be positive"));
+        }
+        let max_flushes = cfg.max_concurrent_flushes.unwrap_or(4);
+        if max_flushes == 0 {
+            return Err(anyhow!("max_concurrent_flushes must be positive"));
+        }
         Ok(Ingester {
-            batch: Vec::new(),
+            batches,
+            exactly_once,
+            partitions: HashMap::new(),
             batch_size: cfg.batch_size.unwrap(),
             batch_timeout: Duration::from_secs(cfg.batch_timeout_seconds.unwrap()),
             pool,
+            inserter: Arc::new(inserter),
+            flushes: JoinSet::new(),
+            in_flight: HashMap::new(),
+            next_flush: 0,
+            max_flushes,
+            max_pending_bytes: cfg.max_pending_bytes.unwrap_or(256 << 20),
             consumer,
-            decoder,
-            topic: cfg.topic,
-            table: cfg.clickhouse_table,
+            decoders,
+            topic_columns: HashMap::new(),
+            topic_names: HashSet::new(),
+            decode_parallelism,
+            dead_letters,
+            metadata,
+            tombstones,
+            router,
+            consumed: HashMap::new(),
+            committed: HashMap::new(),
+            offsets_table: cfg.offsets_table,
+            consumer_group: cfg.consumer_group.unwrap(),
+            topics,
+            metrics,
         })
     }
 
-    /// subscribe to topic and start ingestion process
-    pub async fn start(&mut self) {
+    /// subscribe to topics and start ingestion process, on `shutdown` pending rows are flushed,
+    /// offsets committed and consumer closed leaving the group
+    pub async fn start(mut self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        let topics: Vec<&str> = self.topics.iter().map(String::as_str).collect();
+        self.consumer
+            .subscribe(&topics)
+            .with_context(|| format!("subscribing to {}", self.topics.join(", ")))?;
+        let result = self.consume(&mut shutdown).await;
+        // on failure pending rows are dropped, and partitions are consumed again from committed
+        // offsets by the next owner
+        self.consumer.context().close();
+        // dropping consumer leaves the group, blocking until it is done
+        let consumer = self.consumer;
+        tokio::task::block_in_place(move || drop(consumer));
+        result
+    }
+
+    /// consumes and flushes batches until `shutdown`
+    async fn consume(&mut self, shutdown: &mut watch::Receiver<bool>) -> Result<()> {
         let mut count: usize = 0;
         let mut start = Instant::now();
-        self.consumer.subscribe(&[&self.topic]).unwrap();
         loop {
-            let tpl = self.get_batch().await;
-            if tpl.count() + self.batch.len() == 0 {
-                continue;
+            let timed_out = self.get_batch(shutdown).await?;
+            self.rebalance().await?;
+            count += self.flush_ready(timed_out).await?;
+            count += self.flush_largest().await?;
+            if !self.exactly_once {
+                self.commit().await;
+            }
+            if *shutdown.borrow() {
+                break;
             }
-            count += self.batch.len();
             if count >= 100000 {
                 let elapsed = Instant::now() - start;
                 eprintln!(
@@ -79,77 +366,882 @@ impl Ingester {
                 count = 0;
                 start = Instant::now();
             }
-            self.try_insert(tpl).await;
         }
+        eprintln!("shutting down, closing consumer");
+        // partitions being flushed in exactly-once mode have pending rows flushed after them
+        self.drain().await?;
+        self.flush_ready(true).await?;
+        self.drain().await?;
+        if !self.exactly_once {
+            self.commit().await;
+        }
+        self.rebalance().await
     }
 
-    async fn try_insert(&mut self, tpl: TopicPartitionList) {
-        // keep trying insert to CH until we succeed and only after that commit offsets
-        loop {
-            match self.insert_batch().await {
-                Ok(()) => {
-                    self.consumer
-                        .commit(&tpl, rdkafka::consumer::CommitMode::Sync)
-                        .unwrap_or_else(|e| eprintln!("failed to commit offsets: {e}"));
-                    return;
+    /// flushes batches which are full, or all pending ones on timeout, returns number of rows
+    async fn flush_ready(&mut self, timed_out: bool) -> Result<usize> {
+        let mut count = 0;
+        if self.exactly_once {
+            let ready: Vec<(Arc<str>, i32)> = self
+                .partitions
+                .iter()
+                .filter(|(k, p)| {
+                    let end = self.consumed.get(*k);
+                    end.is_some_and(|end| p.ready(*end, self.batch_size, timed_out))
+                })
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in ready {
+                count += self.flush_partition(&k).await?;
+            }
+        } else {
+            for i in 0..self.batches.len() {
+                let len = self.batches[i].len();
+                if len >= self.batch_size || (timed_out && len > 0) {
+                    count += len;
+                    let batch = self.batches[i].take();
+                    self.flush(vec![batch], None, None).await?;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// flushes batch with the most pending bytes, or batches of such partition in exactly-once
+    /// mode, if pending messages exceed the limit. Returns number of rows
+    async fn flush_largest(&mut self) -> Result<usize> {
+        if !self.over_limit() {
+            return Ok(0);
+        }
+        if self.exactly_once {
+            let Some(k) = self.largest_partition() else {
+                return Ok(0);
+            };
+            return self.flush_partition(&k).await;
+        }
+        let Some(i) = self.largest_batch() else {
+            return Ok(0);
+        };
+        let batch = self.batches[i].take();
+        let count = batch.len();
+        self.flush(vec![batch], None, None).await?;
+        Ok(count)
+    }
+
+    /// returns true if payloads of pending messages exceed `max_pending_bytes` while none
+    /// of batches are being inserted, so that some have to be flushed before they are full
+    fn over_limit(&self) -> bool {
+        self.in_flight.is_empty()
+            && self.pending_bytes() >= self.max_pending_bytes
+            && match self.exactly_once {
+                true => self.largest_partition().is_some(),
+                false => self.largest_batch().is_some(),
+            }
+    }
+
+    /// returns index of non-empty batch with the most pending bytes
+    fn largest_batch(&self) -> Option<usize> {
+        self.batches
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.is_empty())
+            .max_by_key(|(_, b)| b.bytes)
+            .map(|(i, _)| i)
+    }
+
+    /// returns partition with the most pending bytes in exactly-once mode, except of ones
+    /// being flushed or reproducing batch up to its boundary
+    fn largest_partition(&self) -> Option<(Arc<str>, i32)> {
+        self.partitions
+            .iter()
+            .filter(|(_, p)| p.messages > 0 && !p.flushing && p.boundary.is_none())
+            .max_by_key(|(_, p)| {
+                p.batches
+                    .iter()
+                    .map(|i| self.batches[*i].bytes)
+                    .sum::<usize>()
+            })
+            .map(|(k, _)| k.clone())
+    }
+
+    /// starts inserting batches in background, waiting until number of flushes is below the max.
+    /// Offsets of their partitions are committed when they are done, and `end` offset of partition
+    /// in exactly-once mode
+    async fn flush(
+        &mut self,
+        batches: Vec<TableBatch>,
+        token: Option<String>,
+        end: Option<((Arc<str>, i32), i64)>,
+    ) -> Result<()> {
+        while self.in_flight.len() >= self.max_flushes {
+            self.join_flush().await?;
+        }
+        let mut first_offsets = HashMap::new();
+        for (k, offset) in batches.iter().flat_map(|b| &b.first_offsets) {
+            let first = first_offsets.entry(k.clone()).or_insert(*offset);
+            *first = (*first).min(*offset);
+        }
+        let flush = Flush {
+            first_offsets,
+            end,
+            bytes: batches.iter().map(|b| b.bytes).sum(),
+        };
+        let id = self.next_flush;
+        self.next_flush += 1;
+        self.in_flight.insert(id, flush);
+        let inserter = self.inserter.clone();
+        self.flushes.spawn(async move {
+            for batch in &batches {
+                if !batch.is_empty() {
+                    inserter.insert(batch, token.as_deref()).await?;
                 }
-                Err(e) => {
-                    eprintln!("inserting batch: {}, pending rows: {}", e, self.batch.len());
-                    sleep(CH_BACKOFF).await;
+            }
+            Ok(id)
+        });
+        Ok(())
+    }
+
+    /// waits until any of flushes is done
+    async fn join_flush(&mut self) -> Result<()> {
+        match self.flushes.join_next().await {
+            Some(done) => self.flushed(done).await,
+            None => Ok(()),
+        }
+    }
+
+    /// waits until all flushes are done
+    async fn drain(&mut self) -> Result<()> {
+        while !self.in_flight.is_empty() {
+            self.join_flush().await?;
+        }
+        Ok(())
+    }
+
+    /// accounts done flush, committing end offset of its partition in exactly-once mode
+    async fn flushed(&mut self, done: Result<Result<u64>, JoinError>) -> Result<()> {
+        let id = done.context("flush task failed")??;
+        let Some(flush) = self.in_flight.remove(&id) else {
+            return Ok(());
+        };
+        let Some((k, end)) = flush.end else {
+            return Ok(());
+        };
+        // if offset isn't stored, batch is reproduced from the offset stored before
+        match self.store_offsets(&[(k.clone(), end)]).await {
+            Ok(()) => {
+                self.commit_offset(&k, end, None);
+            }
+            Err(e) => {
+                self.metrics.commit_failures.inc();
+                eprintln!("failed to store offset of {}/{}: {e:#}", k.0, k.1);
+            }
+        }
+        if let Some(p) = self.partitions.get_mut(&k) {
+            p.flushing = false;
+        }
+        Ok(())
+    }
+
+    /// returns size of payloads of messages pending insert, including ones being inserted
+    fn pending_bytes(&self) -> usize {
+        let pending: usize = self.batches.iter().map(|b| b.bytes).sum();
+        pending + self.in_flight.values().map(|f| f.bytes).sum::<usize>()
+    }
+
+    /// commits offsets of partitions up to the first row still pending insert into any table,
+    /// including batches being inserted
+    async fn commit(&mut self) {
+        let mut offsets = Vec::new();
+        for (k, next_offset) in &self.consumed {
+            let offset = self
+                .batches
+                .iter()
+                .filter_map(|b| b.first_offset(k))
+                .chain(
+                    self.in_flight
+                        .values()
+                        .filter_map(|f| f.first_offsets.get(k).copied()),
+                )
+                .fold(*next_offset, i64::min);
+            if self.committed.get(k) == Some(&offset) {
+                continue;
+            }
+            offsets.push((k.clone(), offset));
+        }
+        if offsets.is_empty() {
+            return;
+        }
+        if let Err(e) = self.store_offsets(&offsets).await {
+            self.metrics.commit_failures.inc();
+            eprintln!("failed to store offsets: {e:#}");
+            return;
+        }
+        let mut tpl = TopicPartitionList::new();
+        for (k, offset) in &offsets {
+            //commiting _next_ message offset as per https://docs.rs/rdkafka/latest/rdkafka/consumer/trait.Consumer.html#tymethod.commit
+            tpl.add_partition_offset(&k.0, k.1, Offset::from_raw(*offset))
+                .unwrap();
+        }
+        match self
+            .consumer
+            .commit(&tpl, rdkafka::consumer::CommitMode::Sync)
+        {
+            Ok(()) => {
+                for (k, offset) in offsets {
+                    self.committed.insert(k, offset);
                 }
             }
+            Err(e) => {
+                self.metrics.commit_failures.inc();
+                eprintln!("failed to commit offsets: {e}")
+            }
         }
     }
 
-    async fn insert_batch(&mut self) -> Result<(), anyhow::Error> {
-        if self.batch.is_empty() {
+    /// stores offsets of partitions in ClickHouse table, if it is configured
+    async fn store_offsets(&self, offsets: &[((Arc<str>, i32), i64)]) -> Result<()> {
+        let Some(table) = &self.offsets_table else {
             return Ok(());
+        };
+        let mut ch = self.pool.get_handle().await?;
+        if self.exactly_once {
+            // connection may have token of batch inserted before
+            ch.execute("SET insert_deduplication_token = ''").await?;
         }
+        let block = Block::new()
+            .column(
+                "consumer_group",
+                vec![self.consumer_group.clone(); offsets.len()],
+            )
+            .column(
+                "topic",
+                offsets
+                    .iter()
+                    .map(|(k, _)| k.0.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .column(
+                "partition",
+                offsets.iter().map(|(k, _)| k.1).collect::<Vec<_>>(),
+            )
+            .column(
+                "offset",
+                offsets.iter().map(|(_, o)| *o).collect::<Vec<_>>(),
+            );
+        ch.insert(table, block).await?;
+        Ok(())
+    }
 
+    /// returns offsets of partitions stored in ClickHouse table
+    async fn stored_offsets(&self, table: &str) -> Result<HashMap<(String, i32), i64>> {
         let mut ch = self.pool.get_handle().await?;
-        let mut block = Block::with_capacity(self.batch.len());
-        for msg in &self.batch {
-            block.push(msg.to_owned())?;
+        let group = self
+            .consumer_group
+            .replace('\\', "\\\\")
+            .replace('\'', "\\'");
+        let sql = format!(
+            "SELECT topic, partition, max(offset) AS offset FROM {table} \
+             WHERE consumer_group = '{group}' GROUP BY topic, partition"
+        );
+        let block = ch.query(sql).fetch_all().await?;
+        let mut offsets = HashMap::new();
+        for row in block.rows() {
+            let k = (row.get("topic")?, row.get("partition")?);
+            offsets.insert(k, row.get("offset")?);
+        }
+        Ok(offsets)
+    }
+
+    /// applies rebalance deferred by consumer context
+    async fn rebalance(&mut self) -> Result<()> {
+        if let Some(revoked) = self.consumer.context().take_revoked() {
+            self.revoke(revoked).await?;
+        }
+        if let Some(assigned) = self.consumer.context().take_assigned() {
+            self.assign(assigned).await;
+        }
+        Ok(())
+    }
+
+    /// seeks assigned partitions to offsets stored in ClickHouse, if there are such, and takes them
+    async fn assign(&mut self, mut assigned: TopicPartitionList) {
+        if let Some(table) = &self.offsets_table {
+            // partitions have to start from stored offsets, so keep trying until we succeed
+            let offsets = loop {
+                match self.stored_offsets(table).await {
+                    Ok(offsets) => break offsets,
+                    Err(e) => {
+                        eprintln!("failed to fetch offsets from {table}: {e:#}");
+                        sleep(CH_BACKOFF).await;
+                    }
+                }
+            };
+            let partitions: Vec<(String, i32)> = assigned
+                .elements()
+                .iter()
+                .map(|e| (e.topic().to_string(), e.partition()))
+                .collect();
+            for k in partitions {
+                if let Some(offset) = offsets.get(&k) {
+                    assigned
+                        .set_partition_offset(&k.0, k.1, Offset::from_raw(*offset))
+                        .unwrap();
+                }
+            }
         }
-        match ch.insert(&self.table, block).await {
-            Ok(_) => {
-                self.batch.truncate(0);
-                Ok(())
+        let result = match self.consumer.rebalance_protocol() {
+            RebalanceProtocol::Cooperative => self.consumer.incremental_assign(&assigned),
+            _ => self.consumer.assign(&assigned),
+        };
+        if let Err(e) = result {
+            eprintln!("failed to assign partitions: {e}");
+        }
+    }
+
+    /// commits offset of partition, with end offset of batch about to be inserted as metadata
+    fn commit_offset(&mut self, k: &(Arc<str>, i32), offset: i64, end: Option<i64>) -> bool {
+        let mut tpl = TopicPartitionList::new();
+        let mut e = tpl.add_partition(&k.0, k.1);
+        e.set_offset(Offset::from_raw(offset)).unwrap();
+        if let Some(end) = end {
+            e.set_metadata(end.to_string());
+        }
+        match self
+            .consumer
+            .commit(&tpl, rdkafka::consumer::CommitMode::Sync)
+        {
+            Ok(()) => {
+                self.committed.insert(k.clone(), offset);
+                true
             }
-            Err(e) => Err(anyhow!("inserting batch to CH: {}", e)),
+            Err(e) => {
+                self.metrics.commit_failures.inc();
+                eprintln!("failed to commit offset of {}/{}: {e}", k.0, k.1);
+                false
+            }
+        }
+    }
+
+    /// returns committed offset of partition and end offset of batch recorded in its metadata
+    fn boundary(&self, k: &(Arc<str>, i32)) -> Option<(i64, i64)> {
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition(&k.0, k.1);
+        let committed =
+            tokio::task::block_in_place(|| self.consumer.committed_offsets(tpl, KAFKA_TIMEOUT));
+        match committed {
+            Ok(tpl) => tpl.elements().first().and_then(|e| {
+                let end = e.metadata().parse().ok()?;
+                Some((e.offset().to_raw()?, end))
+            }),
+            Err(e) => {
+                eprintln!("failed to fetch committed offset of {}/{}: {e}", k.0, k.1);
+                None
+            }
+        }
+    }
+
+    /// returns index of batch of table `i`, or of partition's batch of that table in exactly-once mode
+    fn batch_index(&self, k: &(Arc<str>, i32), i: usize) -> usize {
+        match self.exactly_once {
+            true => self.partitions[k].batches[i],
+            false => i,
+        }
+    }
+
+    /// starts inserting batches of partition with deduplication token derived from their offsets,
+    /// returns number of flushed rows. Before insert end offset of batches is committed
+    /// as metadata, so that they are reproduced if their offsets are not committed
+    async fn flush_partition(&mut self, k: &(Arc<str>, i32)) -> Result<usize> {
+        let p = &self.partitions[k];
+        let (Some(start), Some(&end)) = (p.start, self.consumed.get(k)) else {
+            return Ok(0);
+        };
+        let (boundary, batches) = (p.boundary, p.batches.clone());
+        // rows stay pending until next flush
+        if boundary != Some(end) && !self.commit_offset(k, start, Some(end)) {
+            return Ok(0);
+        }
+        let token = format!("{}-{}-{}-{}", k.0, k.1, start, end);
+        let batches: Vec<TableBatch> = batches.iter().map(|i| self.batches[*i].take()).collect();
+        let rows = batches.iter().map(|b| b.len()).sum();
+        let p = self.partitions.get_mut(k).unwrap();
+        p.start = None;
+        p.messages = 0;
+        p.boundary = None;
+        p.flushing = true;
+        self.flush(batches, Some(token), Some((k.clone(), end)))
+            .await?;
+        Ok(rows)
+    }
+
+    /// flushes pending rows and commits offsets before giving up revoked partitions,
+    /// or drops pending rows if partitions are already lost to another consumer
+    async fn revoke(&mut self, revoked: TopicPartitionList) -> Result<()> {
+        // offsets are committed in order, so batches being inserted have to be done first
+        self.drain().await?;
+        if self.consumer.assignment_lost() {
+            // assignment is lost as a whole, so none of pending rows can be committed
+            let pending: usize = self.batches.iter().map(|b| b.len()).sum();
+            eprintln!("partitions lost, dropping {pending} pending rows");
+            for batch in &mut self.batches {
+                batch.clear();
+            }
+        } else if self.exactly_once {
+            // batches not reaching their boundary yet are left to the next owner
+            let partitions: Vec<(Arc<str>, i32)> = revoked
+                .elements()
+                .iter()
+                .map(|e| (e.topic().into(), e.partition()))
+                .collect();
+            for k in partitions {
+                let ready = match (self.partitions.get(&k), self.consumed.get(&k)) {
+                    (Some(p), Some(end)) => p.ready(*end, self.batch_size, true),
+                    _ => false,
+                };
+                if ready {
+                    self.flush_partition(&k).await?;
+                }
+            }
+            self.drain().await?;
+        } else {
+            self.flush_ready(true).await?;
+            self.drain().await?;
+            self.commit().await;
+        }
+        for e in revoked.elements() {
+            let k = (e.topic().into(), e.partition());
+            self.consumed.remove(&k);
+            self.committed.remove(&k);
+            self.metrics.remove_consumer_lag(&k.0, k.1);
+            if let Some(p) = self.partitions.get_mut(&k) {
+                p.start = None;
+                p.messages = 0;
+                p.boundary = None;
+                for i in &p.batches {
+                    self.batches[*i].clear();
+                }
+            }
+        }
+        let result = match self.consumer.rebalance_protocol() {
+            RebalanceProtocol::Cooperative => self.consumer.incremental_unassign(&revoked),
+            _ => self.consumer.unassign(),
+        };
+        if let Err(e) = result {
+            eprintln!("failed to unassign revoked partitions: {e}");
         }
+        Ok(())
     }
 
-    async fn get_batch(&mut self) -> TopicPartitionList {
-        let mut topic_map: HashMap<(String, i32), Offset> = HashMap::new();
-        while self.batch.len() < self.batch_size {
-            match tokio::time::timeout(self.batch_timeout, self.consumer.recv()).await {
+    /// returns true if any of batches should be flushed without waiting for timeout,
+    /// except of partitions being flushed in exactly-once mode
+    fn batch_full(&self) -> bool {
+        match self.exactly_once {
+            true => self.partitions.iter().any(|(k, p)| {
+                let end = self.consumed.get(k);
+                end.is_some_and(|end| p.ready(*end, self.batch_size, false))
+            }),
+            false => self.batches.iter().any(|b| b.len() >= self.batch_size),
+        }
+    }
+
+    /// consumes messages until any of batches is full and can be flushed, or pending messages
+    /// exceed the limit, returns true if it stopped on timeout, rebalance or shutdown. Meanwhile accounts done flushes, and pauses
+    /// consuming while there are too many of them or too many pending rows
+    async fn get_batch(&mut self, shutdown: &mut watch::Receiver<bool>) -> Result<bool> {
+        loop {
+            let full = self.batch_full();
+            if (full && self.in_flight.len() < self.max_flushes) || self.over_limit() {
+                return Ok(false);
+            }
+            let paused = !self.in_flight.is_empty()
+                && (full || self.pending_bytes() >= self.max_pending_bytes);
+            let received = tokio::select! {
+                r = tokio::time::timeout(self.batch_timeout, self.consumer.recv()), if !paused => r,
+                Some(done) = self.flushes.join_next() => {
+                    self.flushed(done).await?;
+                    continue;
+                }
+                _ = shutdown.changed() => return Ok(true),
+                _ = self.consumer.context().rebalanced() => return Ok(true),
+            };
+            let msg = match received {
                 Err(_) => {
-                    break;
+                    return Ok(true);
                 }
                 Ok(Err(e)) => {
                     eprintln!("error receiving message: {e}");
-                    break;
+                    return Ok(true);
                 }
-                Ok(Ok(msg)) => {
-                    let k = (msg.topic().to_string(), msg.partition());
-                    let next_offset = msg.offset() + 1; //commiting _next_ message offset as per https://docs.rs/rdkafka/latest/rdkafka/consumer/trait.Consumer.html#tymethod.commit
-                    match topic_map.get(&k) {
-                        None => topic_map.insert(k, Offset::from_raw(next_offset)),
-                        Some(offset) if offset.to_raw().unwrap() < next_offset => {
-                            topic_map.insert(k, Offset::from_raw(next_offset))
-                        }
-                        _ => None,
-                    };
-                    match self.decoder.decode(msg.payload().unwrap()) {
-                        Ok(row) => self.batch.push(row),
-                        Err(err) => {
-                            eprintln!("failed to decode message: {}", err)
-                        }
+                Ok(Ok(msg)) => msg.detach(),
+            };
+            // messages already received are decoded together, as many as fit into batches
+            let mut chunk = vec![msg];
+            let limit = self.room().min(self.decode_parallelism * DECODE_CHUNK);
+            while chunk.len() < limit && !self.unseen(chunk.last().unwrap()) {
+                match tokio::time::timeout(Duration::ZERO, self.consumer.recv()).await {
+                    Ok(Ok(msg)) => chunk.push(msg.detach()),
+                    Ok(Err(e)) => {
+                        eprintln!("error receiving message: {e}");
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+            for (msg, decoded) in self.decode(chunk).await? {
+                self.account(msg, decoded).await?;
+            }
+        }
+    }
+
+    /// returns number of messages that can be added without making any of batches exceed the size,
+    /// or, in exactly-once mode, going past boundary of batches reproduced after restart
+    fn room(&self) -> usize {
+        let room = match self.exactly_once {
+            true => self
+                .partitions
+                .iter()
+                .map(|(k, p)| p.room(self.consumed.get(k).copied(), self.batch_size))
+                .min(),
+            false => self
+                .batches
+                .iter()
+                .map(|b| self.batch_size.saturating_sub(b.len()))
+                .min(),
+        };
+        room.unwrap_or(self.batch_size).max(1)
+    }
+
+    /// returns true if message is the first one of its partition since assignment in
+    /// exactly-once mode, so that boundary of its batches is not known until it's accounted
+    fn unseen(&self, msg: &OwnedMessage) -> bool {
+        self.exactly_once
+            && self.topic_names.get(msg.topic()).is_none_or(|topic| {
+                !self
+                    .consumed
+                    .contains_key(&(topic.clone(), msg.partition()))
+            })
+    }
+
+    /// returns key of message's partition, sharing name of its topic with other keys
+    fn key(&mut self, msg: &OwnedMessage) -> (Arc<str>, i32) {
+        let topic = match self.topic_names.get(msg.topic()) {
+            Some(topic) => topic.clone(),
+            None => {
+                let topic: Arc<str> = Arc::from(msg.topic());
+                self.topic_names.insert(topic.clone());
+                topic
+            }
+        };
+        (topic, msg.partition())
+    }
+
+    /// decodes messages, splitting them between `decode_parallelism` blocking tasks,
+    /// returns them in the same order with decoded values, or none for tombstones.
+    /// Fails if decoder of their topic can't be loaded
+    async fn decode(
+        &mut self,
+        chunk: Vec<OwnedMessage>,
+    ) -> Result<Vec<(OwnedMessage, Option<Result<Vec<Value>>>)>> {
+        let mut jobs = Vec::with_capacity(chunk.len());
+        for msg in chunk {
+            if msg.payload().is_none() {
+                jobs.push((msg, None));
+                continue;
+            }
+            // messages of topic can't be decoded at all, so they are left uncommitted
+            // instead of being dead-lettered, e.g. until registry is available
+            let decoder = self.decoders.get(msg.topic()).await?;
+            if !self.topic_columns.contains_key(msg.topic()) {
+                let columns = decoder
+                    .columns()
+                    .into_iter()
+                    .chain(self.metadata.names().cloned());
+                self.topic_columns
+                    .insert(msg.topic().to_owned(), columns.collect());
+            }
+            jobs.push((msg, Some(decoder)));
+        }
+        if self.decode_parallelism == 1 || jobs.len() == 1 {
+            return Ok(jobs.into_iter().map(decode).collect());
+        }
+        let size = jobs.len().div_ceil(self.decode_parallelism);
+        let mut tasks = Vec::with_capacity(self.decode_parallelism);
+        while !jobs.is_empty() {
+            let rest = jobs.split_off(size.min(jobs.len()));
+            let part = std::mem::replace(&mut jobs, rest);
+            tasks.push(tokio::task::spawn_blocking(move || {
+                part.into_iter().map(decode).collect::<Vec<_>>()
+            }));
+        }
+        let mut decoded = Vec::new();
+        for task in tasks {
+            decoded.extend(task.await.context("decoding task failed")?);
+        }
+        Ok(decoded)
+    }
+
+    /// accounts consumed message, adding its row to batch, or sending it to dead-letter topic
+    /// if it failed to decode. Fails if decoder could not reach service it depends on
+    async fn account(
+        &mut self,
+        msg: OwnedMessage,
+        decoded: Option<Result<Vec<Value>>>,
+    ) -> Result<()> {
+        // message is left uncommitted, to be consumed again after restart
+        let decoded = match decoded {
+            Some(Err(e)) if e.is::<Unavailable>() => {
+                return Err(e.context(format!(
+                    "decoding message {}/{} at offset {}",
+                    msg.topic(),
+                    msg.partition(),
+                    msg.offset()
+                )))
+            }
+            d => d,
+        };
+        self.metrics.consumed.inc();
+        let k = self.key(&msg);
+        let next_offset = msg.offset() + 1;
+        if self.exactly_once {
+            // first message since partition is assigned
+            let boundary = match self.consumed.contains_key(&k) {
+                true => None,
+                false => self.boundary(&k),
+            };
+            self.partitions
+                .entry(k.clone())
+                .or_insert_with(|| PartitionBatches::new(&mut self.batches, self.router.tables()))
+                .track(msg.offset(), boundary);
+        }
+        match self.consumed.get(&k) {
+            Some(offset) if *offset >= next_offset => (),
+            _ => {
+                self.consumed.insert(k.clone(), next_offset);
+            }
+        };
+        let Some(decoded) = decoded else {
+            self.metrics.tombstones.inc();
+            match &self.tombstones {
+                Tombstones::Skip => (),
+                Tombstones::DeadLetter => {
+                    if let Some(dlq) = &self.dead_letters {
+                        dlq.send(&msg, "tombstone").await;
+                    }
+                }
+                Tombstones::Delete(columns) => {
+                    let mut values = vec![Value::UInt8(1)];
+                    self.metadata.append(&msg, &mut values);
+                    let row = Row {
+                        columns: columns.clone(),
+                        values,
                     };
+                    let i = self.batch_index(&k, self.router.route(&msg, &row));
+                    self.batches[i].push(&msg, row);
+                }
+            }
+            return Ok(());
+        };
+        match decoded {
+            Ok(mut values) => {
+                self.metrics.decoded.inc();
+                self.metadata.append(&msg, &mut values);
+                let row = Row {
+                    columns: self.topic_columns[msg.topic()].clone(),
+                    values,
+                };
+                let i = self.batch_index(&k, self.router.route(&msg, &row));
+                self.batches[i].push(&msg, row);
+            }
+            Err(err) => {
+                self.metrics.decode_failures.inc();
+                eprintln!("failed to decode message: {}", err);
+                if let Some(dlq) = &self.dead_letters {
+                    dlq.send(&msg, &format!("{:#}", err)).await;
                 }
             }
+        };
+        Ok(())
+    }
+}
+
+/// decodes payload of message with decoder of its topic, returns none for tombstones
+fn decode(
+    (msg, decoder): (OwnedMessage, Option<Arc<dyn Decoder + Send + Sync>>),
+) -> (OwnedMessage, Option<Result<Vec<Value>>>) {
+    let decoded = decoder.map(|d| d.decode(msg.payload().unwrap_or_default()));
+    (msg, decoded)
+}
+
+/// builds ClickHouse connection options from URL and settings, returns them with number of hosts
+fn clickhouse_options(url: &str, cfg: Option<settings::ClickHouse>) -> Result<(Options, usize)> {
+    let mut options = Options::from_str(url)?;
+    let url_hosts = Url::parse(url)?
+        .query_pairs()
+        .find(|(k, _)| k == "alt_hosts")
+        .map_or(1, |(_, v)| 1 + v.split(',').count());
+    let Some(cfg) = cfg else {
+        return Ok((options, url_hosts));
+    };
+    if let Some(user) = &cfg.user {
+        options = options.username(user);
+    }
+    let password = match (&cfg.password, &cfg.password_env, &cfg.password_file) {
+        (None, None, None) => None,
+        (Some(p), None, None) => Some(p.to_owned()),
+        (None, Some(var), None) => {
+            Some(env::var(var).with_context(|| format!("reading password from {}", var))?)
         }
-        TopicPartitionList::from_topic_map(&topic_map).unwrap()
+        (None, None, Some(f)) => Some(
+            fs::read_to_string(f)
+                .with_context(|| format!("reading password from {}", f))?
+                .trim_end()
+                .to_owned(),
+        ),
+        _ => {
+            return Err(anyhow!(
+                "only one of password, password_env and password_file may be specified"
+            ))
+        }
+    };
+    if let Some(password) = &password {
+        options = options.password(password);
+    }
+    if let Some(secure) = cfg.secure {
+        options = options.secure(secure);
+    }
+    if let Some(skip_verify) = cfg.skip_verify {
+        options = options.skip_verify(skip_verify);
+    }
+    match cfg.compression.as_deref() {
+        None | Some("none") => (),
+        Some("lz4") => options = options.with_compression(),
+        Some(c) => return Err(anyhow!("unsupported compression {}", c)),
+    }
+    if let Some(n) = cfg.pool_min {
+        options = options.pool_min(n);
+    }
+    if let Some(n) = cfg.pool_max {
+        options = options.pool_max(n);
+    }
+    if let Some(ms) = cfg.connection_timeout_ms {
+        options = options.connection_timeout(Duration::from_millis(ms));
+    }
+    if let Some(s) = cfg.query_timeout_seconds {
+        options = options
+            .query_timeout(Duration::from_secs(s))
+            .insert_timeout(Some(Duration::from_secs(s)));
+    }
+    let mut hosts_count = url_hosts;
+    if let Some(hosts) = &cfg.alt_hosts {
+        hosts_count = 1 + hosts.len();
+        let mut urls = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            urls.push(
+                Url::parse(&format!("tcp://{}", host))
+                    .with_context(|| format!("invalid host {}", host))?,
+            );
+        }
+        options = options.alt_hosts(urls);
+    }
+    Ok((options, hosts_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partition(tables: &[&str]) -> (Vec<TableBatch>, PartitionBatches) {
+        let tables: Vec<String> = tables.iter().map(|t| t.to_string()).collect();
+        let mut batches = vec![TableBatch::new("other")];
+        let p = PartitionBatches::new(&mut batches, &tables);
+        (batches, p)
+    }
+
+    #[test]
+    fn partition_batches() {
+        let (batches, p) = partition(&["a", "b"]);
+        assert_eq!(p.batches, vec![1, 2]);
+        assert_eq!(batches[1].table, "a");
+        assert_eq!(batches[2].table, "b");
+    }
+
+    #[test]
+    fn ready_without_boundary() {
+        let (_, mut p) = partition(&["a"]);
+        assert!(!p.ready(0, 3, true));
+        p.track(10, None);
+        p.track(11, None);
+        assert_eq!(p.start, Some(10));
+        assert!(!p.ready(12, 3, false));
+        assert!(p.ready(12, 3, true));
+        p.track(12, None);
+        assert!(p.ready(13, 3, false));
+        p.flushing = true;
+        assert!(!p.ready(13, 3, true));
+    }
+
+    #[test]
+    fn ready_at_boundary() {
+        let (_, mut p) = partition(&["a"]);
+        // batch of offsets 10..20 was about to be inserted before restart
+        p.track(10, Some((10, 20)));
+        assert_eq!(p.boundary, Some(20));
+        for offset in 11..19 {
+            p.track(offset, None);
+            // neither full nor timed out batches are flushed before boundary
+            assert!(!p.ready(offset + 1, 3, true));
+        }
+        p.track(19, None);
+        assert_eq!(p.boundary, Some(20));
+        assert!(p.ready(20, 3, false));
+        assert_eq!((p.start, p.messages), (Some(10), 10));
+    }
+
+    #[test]
+    fn room_up_to_boundary() {
+        let (_, mut p) = partition(&["a"]);
+        assert_eq!(p.room(None, 100), 100);
+        p.track(10, Some((10, 20)));
+        // no more than remaining messages of reproduced batch are accounted at once,
+        // since accounting message at boundary clears it
+        assert_eq!(p.room(Some(11), 100), 9);
+        for offset in 11..19 {
+            p.track(offset, None);
+        }
+        assert_eq!(p.room(Some(19), 100), 1);
+        p.track(19, None);
+        assert_eq!(p.room(Some(20), 100), 0);
+        assert!(p.ready(20, 100, false));
+        // message past boundary would make batch differ from the one before restart
+        p.track(20, None);
+        assert_eq!(p.boundary, None);
+    }
+
+    #[test]
+    fn room_up_to_batch_size() {
+        let (_, mut p) = partition(&["a"]);
+        p.track(10, None);
+        p.track(11, None);
+        assert_eq!(p.room(Some(12), 5), 3);
+        p.flushing = true;
+        assert_eq!(p.room(Some(12), 5), 5);
+    }
+
+    #[test]
+    fn boundary_before_committed_offset() {
+        // consuming from offset stored in ClickHouse, behind the one committed to Kafka
+        let (_, mut p) = partition(&["a"]);
+        p.track(5, Some((10, 20)));
+        assert_eq!(p.boundary, None);
+        assert!(p.ready(6, 3, true));
+    }
+
+    #[test]
+    fn boundary_past_retention() {
+        // messages up to boundary are deleted, so batch is not reproduced
+        let (_, mut p) = partition(&["a"]);
+        p.track(25, Some((10, 20)));
+        assert_eq!(p.boundary, None);
+        assert!(p.ready(26, 3, true));
     }
 }
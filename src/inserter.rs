@@ -0,0 +1,707 @@
+//! Inserts batches of rows into ClickHouse
+//!
+//! Failed inserts are retried according to retry policy, and rows rejected by ClickHouse
+//! are found by bisecting the batch and sent to dead-letter topic, unless all of them are rejected.
+use std::{borrow::Cow, future::Future, ops::Range, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Days, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use clickhouse_rs::{
+    errors::{Error, FromSqlError},
+    types::{RNil, SqlType, Value},
+    Block, Pool,
+};
+use either::Either;
+use rdkafka::Message;
+use tokio::time::sleep;
+
+use crate::{
+    dead_letter::DeadLetterQueue,
+    decoder::{types::zero_value, Row},
+    metrics::Metrics,
+    retry::{self, Retry},
+};
+
+macro_rules! column_values {
+    ($($t:ident: $v:ty),*) => {
+        /// values of single column, kept in typed vector if they are of the same simple type
+        #[derive(Clone)]
+        enum ColumnValues {
+            $($t(Vec<$v>),)*
+            String(Vec<Arc<Vec<u8>>>),
+            /// days since epoch
+            Date(Vec<u16>),
+            /// ticks since epoch, with precision and timezone of values
+            DateTime64(Vec<i64>, (u32, Tz)),
+            /// inner type, values of inner type with zero values in place of NULLs, and NULL flags
+            Nullable(&'static SqlType, Box<ColumnValues>, Vec<bool>),
+            /// values of other types, or of different ones
+            Values(Vec<Value>),
+        }
+
+        impl ColumnValues {
+            fn push(&mut self, v: Value) {
+                match (self, v) {
+                    $((ColumnValues::$t(c), Value::$t(x)) => c.push(x),)*
+                    (ColumnValues::String(c), Value::String(x)) => c.push(x),
+                    (ColumnValues::Date(c), Value::Date(x)) => c.push(x),
+                    (ColumnValues::DateTime64(c, p), Value::DateTime64(x, q)) if *p == q => c.push(x),
+                    (ColumnValues::Nullable(_, c, nulls), Value::Nullable(Either::Right(x))) => {
+                        c.push(*x);
+                        nulls.push(false);
+                    }
+                    (ColumnValues::Nullable(t, c, nulls), Value::Nullable(Either::Left(x))) => {
+                        // type of NULL is declared by decoder, unlike one derived from values
+                        *t = x;
+                        c.push(zero_value(x));
+                        nulls.push(true);
+                    }
+                    // first value chooses type of column
+                    (c @ ColumnValues::Values(_), v) if c.is_empty() => {
+                        *c = match v {
+                            $(Value::$t(x) => ColumnValues::$t(vec![x]),)*
+                            Value::String(x) => ColumnValues::String(vec![x]),
+                            Value::Date(x) => ColumnValues::Date(vec![x]),
+                            Value::DateTime64(x, p) => ColumnValues::DateTime64(vec![x], p),
+                            Value::Nullable(x) => {
+                                let t = match &x {
+                                    Either::Left(t) => *t,
+                                    Either::Right(x) => SqlType::from(x.as_ref().clone()).into(),
+                                };
+                                let values = Box::new(ColumnValues::Values(Vec::new()));
+                                let mut c = ColumnValues::Nullable(t, values, Vec::new());
+                                c.push(Value::Nullable(x));
+                                c
+                            }
+                            v => ColumnValues::Values(vec![v]),
+                        }
+                    }
+                    (ColumnValues::Values(c), v) => c.push(v),
+                    (c, v) => {
+                        let mut values: Vec<Value> = (0..c.len()).map(|i| c.get(i)).collect();
+                        values.push(v);
+                        *c = ColumnValues::Values(values);
+                    }
+                }
+            }
+
+            fn len(&self) -> usize {
+                match self {
+                    $(ColumnValues::$t(c) => c.len(),)*
+                    ColumnValues::String(c) => c.len(),
+                    ColumnValues::Date(c) => c.len(),
+                    ColumnValues::DateTime64(c, _) => c.len(),
+                    ColumnValues::Nullable(_, _, nulls) => nulls.len(),
+                    ColumnValues::Values(c) => c.len(),
+                }
+            }
+
+            fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            fn get(&self, i: usize) -> Value {
+                match self {
+                    $(ColumnValues::$t(c) => Value::$t(c[i]),)*
+                    ColumnValues::String(c) => Value::String(c[i].clone()),
+                    ColumnValues::Date(c) => Value::Date(c[i]),
+                    ColumnValues::DateTime64(c, p) => Value::DateTime64(c[i], *p),
+                    ColumnValues::Nullable(t, _, nulls) if nulls[i] => Value::Nullable(Either::Left(t)),
+                    ColumnValues::Nullable(_, c, _) => Value::Nullable(Either::Right(Box::new(c.get(i)))),
+                    ColumnValues::Values(c) => c[i].clone(),
+                }
+            }
+
+            /// adds column of given rows to block
+            fn add_to(&self, block: Block, name: &str, rows: Range<usize>) -> Result<Block, Error> {
+                Ok(match self {
+                    $(ColumnValues::$t(c) => block.column(name, c[rows].to_vec()),)*
+                    ColumnValues::String(c) => {
+                        let c: Vec<&[u8]> = c[rows].iter().map(|s| s.as_slice()).collect();
+                        block.column(name, c)
+                    }
+                    ColumnValues::Date(c) => {
+                        let c: Vec<NaiveDate> = c[rows].iter().map(|d| to_date(*d)).collect();
+                        block.column(name, c)
+                    }
+                    ColumnValues::DateTime64(c, (precision, tz)) => {
+                        let c: Option<Vec<DateTime<Tz>>> = c[rows.clone()]
+                            .iter()
+                            .map(|x| to_datetime(*x, *precision, *tz))
+                            .collect();
+                        block.column(name, c.ok_or(Error::FromSql(FromSqlError::OutOfRange))?)
+                    }
+                    ColumnValues::Nullable(_, c, nulls) => {
+                        let nulls = &nulls[rows.clone()];
+                        match &**c {
+                            $(ColumnValues::$t(c) => block.column(name, nullable(&c[rows], nulls, |x| Some(*x))),)*
+                            ColumnValues::String(c) => {
+                                block.column(name, nullable(&c[rows], nulls, |s| Some(s.to_vec())))
+                            }
+                            ColumnValues::Date(c) => {
+                                block.column(name, nullable(&c[rows], nulls, |d| Some(to_date(*d))))
+                            }
+                            ColumnValues::DateTime64(c, (precision, tz)) => {
+                                let values = nullable(&c[rows.clone()], nulls, |x| {
+                                    to_datetime(*x, *precision, *tz)
+                                });
+                                if values.iter().zip(nulls).any(|(v, null)| !*null && v.is_none()) {
+                                    return Err(Error::FromSql(FromSqlError::OutOfRange));
+                                }
+                                block.column(name, values)
+                            }
+                            _ => add_values(block, name, rows.map(|i| self.get(i)))?,
+                        }
+                    }
+                    ColumnValues::Values(c) => add_values(block, name, c[rows].iter().cloned())?,
+                })
+            }
+        }
+    };
+}
+
+column_values! {
+    UInt8: u8, UInt16: u16, UInt32: u32, UInt64: u64,
+    Int8: i8, Int16: i16, Int32: i32, Int64: i64,
+    Float32: f32, Float64: f64
+}
+
+/// adds column of values of any type to block, pushing them one by one
+fn add_values(
+    block: Block,
+    name: &str,
+    values: impl ExactSizeIterator<Item = Value>,
+) -> Result<Block, Error> {
+    // single column block builds typed column from values, with the column looked up by empty name
+    let mut column = Block::with_capacity(values.len());
+    for v in values {
+        column.push(RNil.put(Cow::Borrowed(""), v))?;
+    }
+    Ok(block.column(name, column.columns()[0].clone()))
+}
+
+/// converts values into options, which are none for NULLs or values failing to convert
+fn nullable<T, U>(values: &[T], nulls: &[bool], f: impl Fn(&T) -> Option<U>) -> Vec<Option<U>> {
+    values
+        .iter()
+        .zip(nulls)
+        .map(|(v, null)| if *null { None } else { f(v) })
+        .collect()
+}
+
+fn to_date(days: u16) -> NaiveDate {
+    NaiveDate::default() + Days::new(days as u64)
+}
+
+/// converts ticks of given precision into time, unless it's out of range of timestamps
+/// in nanoseconds, as times are converted to them by the driver, which would panic
+fn to_datetime(ticks: i64, precision: u32, tz: Tz) -> Option<DateTime<Tz>> {
+    let scale = 10i64.checked_pow(9u32.checked_sub(precision)?)?;
+    Some(tz.timestamp_nanos(ticks.checked_mul(scale)?))
+}
+
+/// rows with the same columns, e.g. tombstones have other columns than decoded rows,
+/// whose values are kept by column
+pub struct RowBlock {
+    pub columns: Arc<[String]>,
+    values: Vec<ColumnValues>,
+    /// index of each row in batch
+    rows: Vec<usize>,
+}
+
+impl RowBlock {
+    fn new(columns: Arc<[String]>) -> Self {
+        RowBlock {
+            values: vec![ColumnValues::Values(Vec::new()); columns.len()],
+            columns,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// returns columns and values of row
+    pub fn row(&self, i: usize) -> impl Iterator<Item = (&String, Value)> {
+        self.columns
+            .iter()
+            .zip(self.values.iter().map(move |v| v.get(i)))
+    }
+
+    /// builds ClickHouse block of given rows
+    pub fn block(&self, rows: Range<usize>) -> Result<Block, Error> {
+        let mut block = Block::with_capacity(rows.len());
+        for (name, values) in self.columns.iter().zip(&self.values) {
+            block = values.add_to(block, name, rows.clone())?;
+        }
+        Ok(block)
+    }
+}
+
+/// rows pending insert into single table
+pub struct TableBatch {
+    pub table: String,
+    pub blocks: Vec<RowBlock>,
+    /// partitions of rows with offset of their first pending row
+    pub first_offsets: Vec<((Arc<str>, i32), i64)>,
+    /// index of partition in `first_offsets` and offset of message of each row
+    sources: Vec<(usize, i64)>,
+    /// size of payloads of messages of rows
+    pub bytes: usize,
+}
+
+impl TableBatch {
+    pub fn new(table: &str) -> Self {
+        TableBatch {
+            table: table.to_owned(),
+            blocks: Vec::new(),
+            first_offsets: Vec::new(),
+            sources: Vec::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn push<M: Message>(&mut self, msg: &M, row: Row) {
+        // partition of each row is interned once per batch, looking it up from the last one,
+        // as consecutive rows are usually of the same partition
+        let p = match self
+            .first_offsets
+            .iter()
+            .rposition(|((t, p), _)| &**t == msg.topic() && *p == msg.partition())
+        {
+            Some(p) => p,
+            None => {
+                let k = (Arc::from(msg.topic()), msg.partition());
+                self.first_offsets.push((k, msg.offset()));
+                self.first_offsets.len() - 1
+            }
+        };
+        // rows of the same topic share columns
+        let block = match self
+            .blocks
+            .iter()
+            .position(|b| Arc::ptr_eq(&b.columns, &row.columns) || b.columns == row.columns)
+        {
+            Some(i) => &mut self.blocks[i],
+            None => {
+                self.blocks.push(RowBlock::new(row.columns));
+                self.blocks.last_mut().unwrap()
+            }
+        };
+        for (column, v) in block.values.iter_mut().zip(row.values) {
+            column.push(v);
+        }
+        block.rows.push(self.sources.len());
+        self.sources.push((p, msg.offset()));
+        self.bytes += msg.payload().map_or(0, <[u8]>::len);
+    }
+
+    /// returns offset of first pending row of partition
+    pub fn first_offset(&self, k: &(Arc<str>, i32)) -> Option<i64> {
+        self.first_offsets
+            .iter()
+            .find(|(p, _)| p == k)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// returns partition and offset of message of row
+    pub fn source(&self, row: usize) -> (&(Arc<str>, i32), i64) {
+        let (p, offset) = self.sources[row];
+        (&self.first_offsets[p].0, offset)
+    }
+
+    /// returns number of rows
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// takes pending rows, leaving batch empty
+    pub fn take(&mut self) -> Self {
+        std::mem::replace(self, TableBatch::new(&self.table))
+    }
+
+    pub fn clear(&mut self) {
+        self.take();
+    }
+}
+
+pub struct Inserter {
+    pool: Pool,
+    /// number of ClickHouse replicas to try before backing off
+    ch_hosts: usize,
+    retry: Retry,
+    dead_letters: Option<Arc<DeadLetterQueue>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Inserter {
+    pub fn new(
+        pool: Pool,
+        ch_hosts: usize,
+        retry: Retry,
+        dead_letters: Option<Arc<DeadLetterQueue>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Inserter {
+            pool,
+            ch_hosts,
+            retry,
+            dead_letters,
+            metrics,
+        }
+    }
+
+    /// inserts rows of batch, deduplicated by ClickHouse if `token` is given. Rows rejected by
+    /// ClickHouse are sent to dead-letter topic, fails if attempts to insert are exhausted
+    pub async fn insert(&self, batch: &TableBatch, token: Option<&str>) -> Result<()> {
+        let _timer = self.metrics.flush_seconds.start_timer();
+        self.metrics.batch_size.observe(batch.len() as f64);
+        let mut rejected = Vec::new();
+        for (j, block) in batch.blocks.iter().enumerate() {
+            let token = token.map(|t| match j {
+                0 => t.to_owned(),
+                j => format!("{t}-{j}"),
+            });
+            for (i, e) in self
+                .insert_rows(&batch.table, block, token.as_deref())
+                .await?
+            {
+                rejected.push((j, i, e));
+            }
+        }
+        for (j, i, e) in rejected {
+            self.metrics.rows_rejected.inc();
+            let block = &batch.blocks[j];
+            let source = batch.source(block.rows[i]);
+            let ((topic, partition), offset) = source;
+            eprintln!(
+                "row of {topic}/{partition}/{offset} rejected by {}: {e}",
+                batch.table
+            );
+            if let Some(dlq) = &self.dead_letters {
+                dlq.send_row(&batch.table, block.row(i), source, &e).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// inserts rows of block, bisecting them if they are rejected to find rejected rows,
+    /// returns their indexes with the error
+    async fn insert_rows(
+        &self,
+        table: &str,
+        block: &RowBlock,
+        token: Option<&str>,
+    ) -> Result<Vec<(usize, String)>> {
+        bisect(block.len(), token, |rows, token| async move {
+            let n = rows.len() as u64;
+            self.insert_block(table, block, rows, token.as_deref())
+                .await?;
+            self.metrics.rows_inserted.inc_by(n);
+            Ok(())
+        })
+        .await
+        .map_err(|e| anyhow!("inserting batch into {}: {}", table, e))
+    }
+
+    /// inserts given rows of block, retrying with backoff until rows are rejected
+    /// or attempts are exhausted
+    async fn insert_block(
+        &self,
+        table: &str,
+        block: &RowBlock,
+        rows: Range<usize>,
+        token: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut failures = 0;
+        loop {
+            let Err(e) = self.try_insert_block(table, block, &rows, token).await else {
+                return Ok(());
+            };
+            self.metrics.insert_failures.inc();
+            eprintln!("inserting {} rows into {}: {}", rows.len(), table, e);
+            failures += 1;
+            if retry::is_permanent(&e) || self.retry.exhausted(failures) {
+                return Err(e);
+            }
+            // Pool connects to replicas in round-robin, so next attempt goes to the next replica,
+            // and we back off only after all of them have failed
+            if failures % self.ch_hosts == 0 {
+                sleep(self.retry.backoff(failures / self.ch_hosts - 1)).await;
+            }
+        }
+    }
+
+    async fn try_insert_block(
+        &self,
+        table: &str,
+        block: &RowBlock,
+        rows: &Range<usize>,
+        token: Option<&str>,
+    ) -> Result<(), Error> {
+        let data = block.block(rows.clone())?;
+        let mut ch = self.pool.get_handle().await?;
+        if let Some(token) = token {
+            // settings of connection apply to its following queries
+            let set = format!("SET insert_deduplication_token = '{token}'");
+            ch.execute(set).await?;
+        }
+        let _timer = self.metrics.insert_seconds.start_timer();
+        ch.insert(table, data).await.map_err(|e| match e {
+            // columns of block can't be cast to types of table columns, which is caused by
+            // schema of the table rather than by rows, so insert is retried until it's fixed
+            Error::FromSql(e) => {
+                Error::Other(format!("converting block to table types: {e}").into())
+            }
+            e => e,
+        })
+    }
+}
+
+/// inserts `len` rows with `insert`, bisecting them if they are rejected to find rejected rows,
+/// returns their indexes with the error. Parts of rows get their own deduplication tokens
+async fn bisect<F, Fut>(
+    len: usize,
+    token: Option<&str>,
+    mut insert: F,
+) -> Result<Vec<(usize, String)>>
+where
+    F: FnMut(Range<usize>, Option<String>) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let mut rejected = Vec::new();
+    let mut ranges = vec![(0, len)];
+    while let Some((lo, hi)) = ranges.pop() {
+        let token = match token {
+            Some(t) if hi - lo < len => Some(format!("{t}:{lo}-{hi}")),
+            t => t.map(str::to_owned),
+        };
+        match insert(lo..hi, token).await {
+            Ok(()) => (),
+            Err(e) if !retry::is_permanent(&e) => return Err(e.into()),
+            Err(e) if hi - lo == 1 => rejected.push((lo, e.to_string())),
+            Err(_) => {
+                let mid = (lo + hi) / 2;
+                ranges.push((mid, hi));
+                ranges.push((lo, mid));
+            }
+        }
+    }
+    // rather than all of rows being malformed, table doesn't match them,
+    // e.g. after its schema is changed
+    if len > 1 && rejected.len() == len {
+        return Err(anyhow!("all {} rows rejected: {}", len, rejected[0].1));
+    }
+    Ok(rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use clickhouse_rs::types::DateTimeType;
+    use rdkafka::{message::OwnedMessage, Timestamp};
+
+    use super::*;
+
+    fn column(values: Vec<Value>) -> ColumnValues {
+        let mut c = ColumnValues::Values(Vec::new());
+        for v in values {
+            c.push(v);
+        }
+        c
+    }
+
+    fn sql_type(c: &ColumnValues) -> SqlType {
+        let block = c.add_to(Block::new(), "c", 0..c.len()).unwrap();
+        block.columns()[0].sql_type()
+    }
+
+    fn null(t: &'static SqlType) -> Value {
+        Value::Nullable(Either::Left(t))
+    }
+
+    fn some(v: Value) -> Value {
+        Value::Nullable(Either::Right(Box::new(v)))
+    }
+
+    #[test]
+    fn dates() {
+        let c = column(vec![Value::Date(0), Value::Date(19000)]);
+        assert!(matches!(c, ColumnValues::Date(_)));
+        assert_eq!(sql_type(&c), SqlType::Date);
+        assert_eq!(to_date(1), NaiveDate::from_ymd_opt(1970, 1, 2).unwrap());
+    }
+
+    #[test]
+    fn datetimes() {
+        let p = (3, chrono_tz::UTC);
+        let c = column(vec![Value::DateTime64(1500, p), Value::DateTime64(-1, p)]);
+        assert!(matches!(c, ColumnValues::DateTime64(_, _)));
+        assert_eq!(sql_type(&c), SqlType::DateTime(DateTimeType::Chrono));
+        assert_eq!(c.get(1), Value::DateTime64(-1, p));
+        assert_eq!(
+            to_datetime(1500, 3, chrono_tz::UTC)
+                .unwrap()
+                .timestamp_millis(),
+            1500
+        );
+        // out of range of timestamps in nanoseconds, so the row is rejected
+        let c = column(vec![Value::DateTime64(i64::MAX, p)]);
+        assert!(c.add_to(Block::new(), "c", 0..1).is_err());
+        let t = SqlType::DateTime(DateTimeType::DateTime64(3, p.1)).into();
+        let c = column(vec![null(t), some(Value::DateTime64(i64::MAX, p))]);
+        assert!(c.add_to(Block::new(), "c", 0..1).is_ok());
+        assert!(c.add_to(Block::new(), "c", 0..2).is_err());
+        // values of different precision
+        let c = column(vec![
+            Value::DateTime64(1, p),
+            Value::DateTime64(1, (6, chrono_tz::UTC)),
+        ]);
+        assert!(matches!(c, ColumnValues::Values(_)));
+    }
+
+    #[test]
+    fn nullables() {
+        let c = column(vec![null(&SqlType::Int32), some(Value::Int32(5))]);
+        let ColumnValues::Nullable(t, inner, nulls) = &c else {
+            panic!("expected nullable column")
+        };
+        assert_eq!(**t, SqlType::Int32);
+        assert!(matches!(**inner, ColumnValues::Int32(_)));
+        assert_eq!(nulls, &[true, false]);
+        assert_eq!(c.get(0), null(&SqlType::Int32));
+        assert_eq!(c.get(1), some(Value::Int32(5)));
+        assert_eq!(sql_type(&c), SqlType::Nullable(&SqlType::Int32));
+
+        let c = column(vec![some(Value::from("a")), null(&SqlType::String)]);
+        assert_eq!(sql_type(&c), SqlType::Nullable(&SqlType::String));
+        let c = column(vec![some(Value::Date(1)), null(&SqlType::Date)]);
+        assert_eq!(sql_type(&c), SqlType::Nullable(&SqlType::Date));
+        let p = (6, chrono_tz::UTC);
+        let t = SqlType::DateTime(DateTimeType::DateTime64(6, p.1)).into();
+        let c = column(vec![null(t), some(Value::DateTime64(7, p))]);
+        assert!(matches!(&c, ColumnValues::Nullable(_, inner, _)
+            if matches!(**inner, ColumnValues::DateTime64(_, _))));
+        assert_eq!(c.get(1), some(Value::DateTime64(7, p)));
+        assert_eq!(
+            sql_type(&c),
+            SqlType::Nullable(SqlType::DateTime(DateTimeType::Chrono).into())
+        );
+    }
+
+    #[test]
+    fn mixed_values() {
+        let c = column(vec![Value::Int32(1), some(Value::Int32(2))]);
+        assert!(matches!(c, ColumnValues::Values(_)));
+        assert_eq!(c.get(0), Value::Int32(1));
+        assert_eq!(c.get(1), some(Value::Int32(2)));
+    }
+
+    #[test]
+    fn sources() {
+        let columns: Arc<[String]> = vec![String::from("c")].into();
+        let mut batch = TableBatch::new("t");
+        for (topic, partition, offset) in [("a", 0, 10), ("a", 0, 11), ("b", 0, 5), ("a", 0, 12)] {
+            let msg = OwnedMessage::new(
+                None,
+                None,
+                String::from(topic),
+                Timestamp::NotAvailable,
+                partition,
+                offset,
+                None,
+            );
+            let row = Row {
+                columns: columns.clone(),
+                values: vec![Value::from(offset)],
+            };
+            batch.push(&msg, row);
+        }
+        assert_eq!(batch.len(), 4);
+        assert_eq!(batch.first_offsets.len(), 2);
+        assert_eq!(batch.first_offset(&("a".into(), 0)), Some(10));
+        assert_eq!(batch.first_offset(&("b".into(), 0)), Some(5));
+        assert_eq!(batch.first_offset(&("a".into(), 1)), None);
+        let (k, offset) = batch.source(3);
+        assert_eq!((&*k.0, k.1, offset), ("a", 0, 12));
+        let (k, offset) = batch.source(2);
+        assert_eq!((&*k.0, k.1, offset), ("b", 0, 5));
+    }
+
+    /// bisects `len` rows with fake insert rejecting ranges that contain any of `bad` rows,
+    /// returns result and inserted ranges with their tokens
+    async fn bisect_rows(
+        len: usize,
+        bad: &[usize],
+        token: Option<&str>,
+    ) -> (
+        Result<Vec<(usize, String)>>,
+        Vec<(Range<usize>, Option<String>)>,
+    ) {
+        let mut inserted = Vec::new();
+        let result = bisect(len, token, |rows, token| {
+            // rows are rejected by permanent error
+            let result = match bad.iter().any(|i| rows.contains(i)) {
+                true => Err(Error::FromSql(FromSqlError::OutOfRange)),
+                false => {
+                    inserted.push((rows, token));
+                    Ok(())
+                }
+            };
+            std::future::ready(result)
+        })
+        .await;
+        (result, inserted)
+    }
+
+    #[tokio::test]
+    async fn bisect_rejected_rows() {
+        let (result, inserted) = bisect_rows(8, &[], Some("t")).await;
+        assert!(result.unwrap().is_empty());
+        assert_eq!(inserted, [(0..8, Some("t".to_owned()))]);
+
+        let (result, inserted) = bisect_rows(8, &[2, 5], Some("t")).await;
+        let rejected: Vec<usize> = result.unwrap().into_iter().map(|(i, _)| i).collect();
+        assert_eq!(rejected, [2, 5]);
+        let mut rows: Vec<usize> = inserted.iter().flat_map(|(r, _)| r.clone()).collect();
+        rows.sort();
+        assert_eq!(rows, [0, 1, 3, 4, 6, 7]);
+        // parts are deduplicated separately
+        assert!(inserted.contains(&(0..2, Some("t:0-2".to_owned()))));
+        assert!(inserted.contains(&(3..4, Some("t:3-4".to_owned()))));
+
+        let (result, inserted) = bisect_rows(1, &[0], None).await;
+        assert_eq!(result.unwrap().len(), 1);
+        assert!(inserted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bisect_all_rows_rejected() {
+        let (result, inserted) = bisect_rows(4, &[0, 1, 2, 3], None).await;
+        let err = result.unwrap_err().to_string();
+        assert!(err.starts_with("all 4 rows rejected"), "{}", err);
+        assert!(inserted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bisect_transient_error() {
+        let mut attempts = 0;
+        let result = bisect(4, None, |_, _| {
+            attempts += 1;
+            std::future::ready(Err(Error::Other("timeout".into())))
+        })
+        .await;
+        assert!(result.is_err());
+        // transient error is not bisected
+        assert_eq!(attempts, 1);
+    }
+}
@@ -6,7 +6,10 @@
 //!
 //! ## Configuration
 //! Example config:
-//! ```
+//! ```toml
+//! metrics_address = "0.0.0.0:9090"        # serve prometheus metrics on http://0.0.0.0:9090/metrics
+//! shutdown_timeout_seconds = 20           # on SIGTERM flush pending rows and commit offsets within 20s
+//!
 //! [ingesters.example]
 //! decoder = "avro"                        # using generic avro decoder
 //! kafka_broker = "localhost:9091"
@@ -17,11 +20,14 @@
 //! clickhouse_table = "test_chafka_avro"
 //! custom.schema_file = "./example.avsc"   # take schema from local file
 //! custom.field_names = { c = "c_arr" }    # field "c" is ingested into column "c_arr"
+//! dead_letter_topic = "test-topic-dlq"    # messages that failed to decode go here
+//! metadata_columns = { kafka_offset = "_offset" } # offset of message is ingested into column "kafka_offset"
 //! ```
 //!
 //! ## Extending
-//! While this service contains generic decoder [avro],
-//! that can be used for ingesting relatively simple avro messages (without nested records),
+//! While this service contains generic decoders [avro] and [protobuf],
+//! that can be used for ingesting relatively simple messages,
+//! and [json] decoder configured with explicit column mapping,
 //! this service was meant to be extended to support different serialization formats and CH tables
 //! by writing own implementations of [Decoder] trait.
 //! Ultimately, you may have own decoder for each topic you are ingesting.
@@ -30,6 +36,8 @@
 //!
 //! [Decoder]: decoder::Decoder
 //! [avro]: decoder::avro
+//! [protobuf]: decoder::protobuf
+//! [json]: decoder::json
 //! [example]: decoder::example
 //!
 //! ## Kafka and ClickHouse
@@ -40,6 +48,13 @@
 //! Batching is controlled by batch size and batch timeout, allowing user to tune
 //! ingestion process either for throughput or for latency.
 
+pub mod context;
+pub mod dead_letter;
 pub mod decoder;
 pub mod ingester;
+pub mod inserter;
+pub mod metadata;
+pub mod metrics;
+pub mod retry;
+pub mod routing;
 pub mod settings;
@@ -1,6 +1,13 @@
-use chafka::{ingester::Ingester, settings::Settings};
+use chafka::{ingester::Ingester, metrics, settings::Settings};
 use clap::Parser;
-use tokio::task::JoinSet;
+use std::{future, process, time::Duration};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinSet,
+    time::timeout,
+};
 
 #[doc(hidden)]
 #[derive(Parser, Debug)]
@@ -14,14 +21,76 @@ struct Args {
 async fn main() {
     let args = Args::parse();
     let settings = Settings::new(&args.config).expect("cannot load config");
+    let mut metrics_server = match settings.metrics_address {
+        None => None,
+        Some(address) => {
+            let listener = metrics::bind(&address)
+                .await
+                .unwrap_or_else(|e| panic!("cannot serve metrics: {:#}", e));
+            Some(tokio::spawn(metrics::serve(listener)))
+        }
+    };
+    let shutdown_timeout = Duration::from_secs(settings.shutdown_timeout_seconds.unwrap_or(20));
+    let (shutdown, shutdown_rx) = watch::channel(false);
     let mut ingesters = JoinSet::new();
     for (name, cfg) in settings.ingesters {
+        let shutdown_rx = shutdown_rx.clone();
         ingesters.spawn(async move {
-            let mut ingester = Ingester::new(cfg)
+            let ingester = Ingester::new(&name, cfg)
                 .await
                 .unwrap_or_else(|e| panic!("failed to create ingester {name}: {:#}", e));
-            ingester.start().await;
+            ingester
+                .start(shutdown_rx)
+                .await
+                .unwrap_or_else(|e| panic!("ingester {name} failed: {:#}", e));
         });
     }
-    while let Some(_) = ingesters.join_next().await {}
+    let mut sigterm = signal(SignalKind::terminate()).expect("cannot handle SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("cannot handle SIGINT");
+    // failure of any ingester, or of metrics endpoint, stops the ingesters,
+    // so that process is restarted
+    let failed = tokio::select! {
+        _ = sigterm.recv() => false,
+        _ = sigint.recv() => false,
+        result = async {
+            match &mut metrics_server {
+                Some(server) => server.await,
+                None => future::pending().await,
+            }
+        } => {
+            match result {
+                Ok(Err(e)) => eprintln!("metrics endpoint failed: {:#}", e),
+                Ok(Ok(())) => eprintln!("metrics endpoint stopped"),
+                Err(e) => eprintln!("metrics endpoint failed: {}", e),
+            }
+            true
+        }
+        failed = async {
+            while let Some(result) = ingesters.join_next().await {
+                if result.is_err() {
+                    return true;
+                }
+            }
+            false
+        } => {
+            if !failed {
+                return;
+            }
+            true
+        }
+    };
+    eprintln!("shutting down, flushing pending rows");
+    shutdown.send_replace(true);
+    if timeout(shutdown_timeout, async {
+        while ingesters.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        eprintln!("ingesters did not stop in {:?}, exiting", shutdown_timeout);
+        process::exit(1);
+    }
+    if failed {
+        process::exit(1);
+    }
 }
@@ -0,0 +1,82 @@
+//! Columns filled from Kafka message metadata instead of its payload
+//!
+//! Each column is mapped onto one of the message fields:
+//! * `_key` - message key, as `String` (empty if there is no key)
+//! * `_topic` - topic, as `String`
+//! * `_partition` - partition, as `Int32`
+//! * `_offset` - offset, as `Int64`
+//! * `_timestamp` - message timestamp, as `DateTime64(3)` (epoch if there is no timestamp)
+//! * `_header.<name>` - value of named header, as `String` (empty if there is no such header)
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use clickhouse_rs::types::Value;
+use rdkafka::{message::Headers, Message};
+
+enum Field {
+    Key,
+    Topic,
+    Partition,
+    Offset,
+    Timestamp,
+    Header(String),
+}
+
+pub struct MetadataColumns {
+    columns: Vec<(String, Field)>,
+}
+
+impl MetadataColumns {
+    /// creates columns from map of column names and message fields
+    pub fn new(columns: HashMap<String, String>) -> Result<Self> {
+        let mut fields = Vec::with_capacity(columns.len());
+        for (column, field) in columns {
+            let field = match field.as_str() {
+                "_key" => Field::Key,
+                "_topic" => Field::Topic,
+                "_partition" => Field::Partition,
+                "_offset" => Field::Offset,
+                "_timestamp" => Field::Timestamp,
+                f => match f.strip_prefix("_header.") {
+                    Some(name) => Field::Header(name.to_owned()),
+                    None => return Err(anyhow!("column {}: unknown message field {}", column, f)),
+                },
+            };
+            fields.push((column, field));
+        }
+        Ok(MetadataColumns { columns: fields })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// returns names of metadata columns, in order their values are appended
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.columns.iter().map(|(column, _)| column)
+    }
+
+    /// appends values of metadata columns of message to decoded values
+    pub fn append<M: Message>(&self, msg: &M, values: &mut Vec<Value>) {
+        for (_, field) in &self.columns {
+            let value = match field {
+                Field::Key => Value::String(Arc::new(msg.key().unwrap_or_default().to_vec())),
+                Field::Topic => Value::from(msg.topic()),
+                Field::Partition => Value::from(msg.partition()),
+                Field::Offset => Value::from(msg.offset()),
+                Field::Timestamp => Value::DateTime64(
+                    msg.timestamp().to_millis().unwrap_or_default(),
+                    (3, chrono_tz::UTC),
+                ),
+                Field::Header(name) => {
+                    let header = msg
+                        .headers()
+                        .and_then(|h| h.iter().find(|h| h.key == name))
+                        .and_then(|h| h.value);
+                    Value::String(Arc::new(header.unwrap_or_default().to_vec()))
+                }
+            };
+            values.push(value);
+        }
+    }
+}
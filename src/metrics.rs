@@ -0,0 +1,206 @@
+//! Prometheus metrics of ingesters and HTTP endpoint exposing them
+use std::{convert::Infallible, sync::LazyLock};
+
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use tokio::net::TcpListener;
+
+struct Registry {
+    consumed: IntCounterVec,
+    decoded: IntCounterVec,
+    decode_failures: IntCounterVec,
+    tombstones: IntCounterVec,
+    rows_inserted: IntCounterVec,
+    insert_failures: IntCounterVec,
+    rows_rejected: IntCounterVec,
+    commit_failures: IntCounterVec,
+    batch_size: HistogramVec,
+    flush_seconds: HistogramVec,
+    insert_seconds: HistogramVec,
+    consumer_lag: IntGaugeVec,
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| Registry {
+    consumed: register_int_counter_vec!(
+        "chafka_messages_consumed_total",
+        "Messages consumed from Kafka",
+        &["ingester"]
+    )
+    .unwrap(),
+    decoded: register_int_counter_vec!(
+        "chafka_messages_decoded_total",
+        "Messages successfully decoded into rows",
+        &["ingester"]
+    )
+    .unwrap(),
+    decode_failures: register_int_counter_vec!(
+        "chafka_decode_failures_total",
+        "Messages that failed to decode",
+        &["ingester"]
+    )
+    .unwrap(),
+    tombstones: register_int_counter_vec!(
+        "chafka_tombstones_total",
+        "Consumed messages without payload",
+        &["ingester"]
+    )
+    .unwrap(),
+    rows_inserted: register_int_counter_vec!(
+        "chafka_rows_inserted_total",
+        "Rows inserted into ClickHouse",
+        &["ingester"]
+    )
+    .unwrap(),
+    insert_failures: register_int_counter_vec!(
+        "chafka_insert_failures_total",
+        "Failed ClickHouse inserts, retried unless rows are rejected",
+        &["ingester"]
+    )
+    .unwrap(),
+    rows_rejected: register_int_counter_vec!(
+        "chafka_rows_rejected_total",
+        "Rows rejected by ClickHouse, sent to dead-letter topic if it's configured",
+        &["ingester"]
+    )
+    .unwrap(),
+    commit_failures: register_int_counter_vec!(
+        "chafka_commit_failures_total",
+        "Failed Kafka offset commits",
+        &["ingester"]
+    )
+    .unwrap(),
+    batch_size: register_histogram_vec!(
+        "chafka_batch_size",
+        "Number of rows in flushed batch",
+        &["ingester"],
+        exponential_buckets(1.0, 4.0, 10).unwrap()
+    )
+    .unwrap(),
+    flush_seconds: register_histogram_vec!(
+        "chafka_batch_flush_seconds",
+        "Time to insert batch, including retries and sending rejected rows to dead-letter topic",
+        &["ingester"],
+        exponential_buckets(0.005, 2.0, 14).unwrap()
+    )
+    .unwrap(),
+    insert_seconds: register_histogram_vec!(
+        "chafka_clickhouse_insert_seconds",
+        "Latency of single ClickHouse insert",
+        &["ingester"],
+        exponential_buckets(0.005, 2.0, 14).unwrap()
+    )
+    .unwrap(),
+    consumer_lag: register_int_gauge_vec!(
+        "chafka_consumer_lag",
+        "Difference between high watermark and committed offset of partition",
+        &["ingester", "topic", "partition"]
+    )
+    .unwrap(),
+});
+
+/// metrics of single ingester
+pub struct Metrics {
+    name: String,
+    pub consumed: IntCounter,
+    pub decoded: IntCounter,
+    pub decode_failures: IntCounter,
+    pub tombstones: IntCounter,
+    pub rows_inserted: IntCounter,
+    pub insert_failures: IntCounter,
+    pub rows_rejected: IntCounter,
+    pub commit_failures: IntCounter,
+    pub batch_size: Histogram,
+    pub flush_seconds: Histogram,
+    pub insert_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn new(ingester: &str) -> Self {
+        let r = &*REGISTRY;
+        let l = &[ingester];
+        Metrics {
+            name: ingester.to_owned(),
+            consumed: r.consumed.with_label_values(l),
+            decoded: r.decoded.with_label_values(l),
+            decode_failures: r.decode_failures.with_label_values(l),
+            tombstones: r.tombstones.with_label_values(l),
+            rows_inserted: r.rows_inserted.with_label_values(l),
+            insert_failures: r.insert_failures.with_label_values(l),
+            rows_rejected: r.rows_rejected.with_label_values(l),
+            commit_failures: r.commit_failures.with_label_values(l),
+            batch_size: r.batch_size.with_label_values(l),
+            flush_seconds: r.flush_seconds.with_label_values(l),
+            insert_seconds: r.insert_seconds.with_label_values(l),
+        }
+    }
+
+    pub fn set_consumer_lag(&self, topic: &str, partition: i32, lag: i64) {
+        REGISTRY
+            .consumer_lag
+            .with_label_values(&[&self.name, topic, &partition.to_string()])
+            .set(lag);
+    }
+
+    /// removes lag of partition, e.g. once it's revoked, so that it's not exported anymore
+    pub fn remove_consumer_lag(&self, topic: &str, partition: i32) {
+        // lag may not be known yet
+        let _ =
+            REGISTRY
+                .consumer_lag
+                .remove_label_values(&[&self.name, topic, &partition.to_string()]);
+    }
+}
+
+/// binds listener of metrics endpoint to given address
+pub async fn bind(address: &str) -> Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("binding metrics listener to {address}"))
+}
+
+/// serves metrics on `/metrics` path of bound listener
+pub async fn serve(listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(handle))
+                .await
+            {
+                eprintln!("serving metrics: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Full::new(Bytes::from_static(b"not found\n")));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        let mut resp = Response::new(Full::new(Bytes::from(e.to_string())));
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(resp);
+    }
+    let mut resp = Response::new(Full::new(Bytes::from(buf)));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(resp)
+}
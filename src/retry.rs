@@ -0,0 +1,127 @@
+//! Retry policy of ClickHouse inserts
+use std::time::Duration;
+
+use clickhouse_rs::errors::{codes, Error};
+
+use crate::settings;
+
+/// server errors caused by inserted data, which fail on retry as well
+const PERMANENT_CODES: &[u32] = &[
+    codes::CANNOT_PARSE_TEXT,
+    codes::CANNOT_PARSE_ESCAPE_SEQUENCE,
+    codes::CANNOT_PARSE_QUOTED_STRING,
+    codes::CANNOT_PARSE_INPUT_ASSERTION_FAILED,
+    codes::CANNOT_PARSE_DATE,
+    codes::CANNOT_PARSE_DATETIME,
+    codes::CANNOT_PARSE_NUMBER,
+    codes::CANNOT_PARSE_UUID,
+    codes::CANNOT_PARSE_BOOL,
+    codes::CANNOT_PARSE_DOMAIN_VALUE_FROM_STRING,
+    codes::TYPE_MISMATCH,
+    codes::CANNOT_CONVERT_TYPE,
+    codes::ARGUMENT_OUT_OF_BOUND,
+    codes::INCORRECT_DATA,
+    codes::TOO_LARGE_STRING_SIZE,
+    codes::TOO_LARGE_ARRAY_SIZE,
+    codes::SIZES_OF_ARRAYS_DOESNT_MATCH,
+    codes::VALUE_IS_OUT_OF_RANGE_OF_DATA_TYPE,
+    codes::CANNOT_INSERT_NULL_IN_ORDINARY_COLUMN,
+    codes::DECIMAL_OVERFLOW,
+    codes::VIOLATED_CONSTRAINT,
+];
+
+/// returns true if insert failed because of rows, rather than of ClickHouse or network
+pub fn is_permanent(e: &Error) -> bool {
+    match e {
+        // rows can't be converted into block, e.g. values of column are of different types
+        Error::FromSql(_) => true,
+        Error::Server(e) => PERMANENT_CODES.contains(&e.code),
+        _ => false,
+    }
+}
+
+pub struct Retry {
+    backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl Retry {
+    pub fn new(cfg: Option<&settings::ClickHouse>) -> Self {
+        let backoff = cfg.and_then(|c| c.retry_backoff_ms).unwrap_or(1000);
+        let max_backoff = cfg.and_then(|c| c.retry_max_backoff_seconds).unwrap_or(60);
+        Retry {
+            backoff: Duration::from_millis(backoff),
+            max_backoff: Duration::from_secs(max_backoff),
+            max_attempts: cfg.and_then(|c| c.retry_max_attempts),
+        }
+    }
+
+    /// returns true if no more attempts are allowed after given number of failed ones
+    pub fn exhausted(&self, attempts: usize) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+
+    /// returns delay before retry after given number of backoffs, doubling with each of them
+    /// up to the max, and randomized by up to a half to spread retries of ingesters
+    pub fn backoff(&self, backoffs: usize) -> Duration {
+        let exp = self
+            .backoff
+            .saturating_mul(1 << backoffs.min(16))
+            .min(self.max_backoff);
+        exp.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clickhouse_rs::errors::{FromSqlError, ServerError};
+
+    use super::*;
+
+    fn server_error(code: u32) -> Error {
+        Error::Server(ServerError {
+            code,
+            name: String::new(),
+            message: String::new(),
+            stack_trace: String::new(),
+        })
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(is_permanent(&Error::FromSql(FromSqlError::OutOfRange)));
+        assert!(is_permanent(&server_error(codes::TYPE_MISMATCH)));
+        assert!(!is_permanent(&server_error(codes::TOO_MANY_PARTS)));
+        assert!(!is_permanent(&Error::Other("timeout".into())));
+    }
+
+    #[test]
+    fn backoff() {
+        let retry = Retry {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
+        };
+        for _ in 0..100 {
+            let b = retry.backoff(0);
+            assert!(b >= Duration::from_millis(500) && b <= Duration::from_secs(1));
+            let b = retry.backoff(2);
+            assert!(b >= Duration::from_secs(2) && b <= Duration::from_secs(4));
+            // capped by max backoff, without overflow
+            for backoffs in [4, 64, usize::MAX] {
+                let b = retry.backoff(backoffs);
+                assert!(b >= Duration::from_secs(5) && b <= Duration::from_secs(10));
+            }
+        }
+    }
+
+    #[test]
+    fn exhausted() {
+        let mut retry = Retry::new(None);
+        assert!(!retry.exhausted(usize::MAX));
+        retry.max_attempts = Some(3);
+        assert!(!retry.exhausted(2));
+        assert!(retry.exhausted(3));
+    }
+}
@@ -0,0 +1,199 @@
+//! Routes decoded rows to ClickHouse tables by message content
+use std::{borrow::Cow, collections::HashSet};
+
+use anyhow::{anyhow, Result};
+use clickhouse_rs::types::Value;
+use rdkafka::{message::Headers, Message};
+
+use crate::{decoder::Row, settings};
+
+/// part of message matched by route
+enum Source {
+    Column(String),
+    Header(String),
+    Key,
+}
+
+struct Route {
+    source: Source,
+    values: HashSet<String>,
+    /// index of table in router's tables
+    table: usize,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    tables: Vec<String>,
+}
+
+impl Router {
+    /// creates router sending rows not matched by any of `routes` to `default_table`
+    pub fn new(default_table: String, routes: Vec<settings::Route>) -> Result<Self> {
+        let mut tables = vec![default_table];
+        let mut rules = Vec::with_capacity(routes.len());
+        for r in routes {
+            let source = match (r.column, r.header, r.key.unwrap_or(false)) {
+                (Some(c), None, false) => Source::Column(c),
+                (None, Some(h), false) => Source::Header(h),
+                (None, None, true) => Source::Key,
+                _ => {
+                    return Err(anyhow!(
+                        "route to {}: exactly one of column, header or key must be specified",
+                        r.table
+                    ))
+                }
+            };
+            let table = match tables.iter().position(|t| *t == r.table) {
+                Some(i) => i,
+                None => {
+                    tables.push(r.table);
+                    tables.len() - 1
+                }
+            };
+            rules.push(Route {
+                source,
+                values: r.values.into_iter().collect(),
+                table,
+            });
+        }
+        Ok(Router {
+            routes: rules,
+            tables,
+        })
+    }
+
+    /// returns all tables rows may be routed to, first one is the default table
+    pub fn tables(&self) -> &[String] {
+        &self.tables
+    }
+
+    /// returns index of the table row of message should be ingested into
+    pub fn route<M: Message>(&self, msg: &M, row: &Row) -> usize {
+        for r in &self.routes {
+            let value = match &r.source {
+                Source::Column(column) => match row.get(column) {
+                    Some(Value::String(s)) => Some(String::from_utf8_lossy(s)),
+                    Some(v) => Some(Cow::Owned(v.to_string())),
+                    None => None,
+                },
+                Source::Header(name) => msg
+                    .headers()
+                    .and_then(|h| h.iter().find(|h| h.key == name))
+                    .and_then(|h| h.value)
+                    .map(String::from_utf8_lossy),
+                Source::Key => msg.key().map(String::from_utf8_lossy),
+            };
+            if value.is_some_and(|v| r.values.contains(v.as_ref())) {
+                return r.table;
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rdkafka::{
+        message::{Header, OwnedHeaders, OwnedMessage},
+        Timestamp,
+    };
+
+    use super::*;
+
+    fn route(
+        table: &str,
+        column: Option<&str>,
+        header: Option<&str>,
+        values: &[&str],
+    ) -> settings::Route {
+        settings::Route {
+            table: table.to_owned(),
+            column: column.map(str::to_owned),
+            header: header.map(str::to_owned),
+            key: None,
+            values: values.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    fn message(key: Option<&str>, header: Option<&str>) -> OwnedMessage {
+        let headers = header.map(|v| {
+            OwnedHeaders::new().insert(Header {
+                key: "type",
+                value: Some(v),
+            })
+        });
+        let key = key.map(|k| k.as_bytes().to_vec());
+        OwnedMessage::new(
+            None,
+            key,
+            "t".to_owned(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            headers,
+        )
+    }
+
+    fn row(kind: &str, version: i32) -> Row {
+        Row {
+            columns: Arc::from(vec!["kind".to_owned(), "version".to_owned()]),
+            values: vec![Value::from(kind), Value::Int32(version)],
+        }
+    }
+
+    #[test]
+    fn routes() {
+        let mut by_key = route("keyed", None, None, &["k"]);
+        by_key.key = Some(true);
+        let router = Router::new(
+            "default".to_owned(),
+            vec![
+                route("clicks", Some("kind"), None, &["click", "tap"]),
+                route("v2", Some("version"), None, &["2"]),
+                route("clicks", None, Some("type"), &["click"]),
+                by_key,
+            ],
+        )
+        .unwrap();
+        assert_eq!(router.tables(), ["default", "clicks", "v2", "keyed"]);
+
+        let msg = message(None, None);
+        assert_eq!(router.route(&msg, &row("tap", 1)), 1);
+        assert_eq!(router.route(&msg, &row("view", 2)), 2);
+        assert_eq!(router.route(&msg, &row("view", 1)), 0);
+        // first matching route wins
+        assert_eq!(router.route(&msg, &row("click", 2)), 1);
+        assert_eq!(
+            router.route(&message(None, Some("click")), &row("view", 1)),
+            1
+        );
+        assert_eq!(
+            router.route(&message(None, Some("view")), &row("view", 1)),
+            0
+        );
+        assert_eq!(router.route(&message(Some("k"), None), &row("view", 1)), 3);
+        assert_eq!(router.route(&message(Some("x"), None), &row("view", 1)), 0);
+    }
+
+    #[test]
+    fn missing_column() {
+        let router = Router::new(
+            "default".to_owned(),
+            vec![route("other", Some("absent"), None, &[""])],
+        )
+        .unwrap();
+        assert_eq!(router.route(&message(None, None), &row("tap", 1)), 0);
+    }
+
+    #[test]
+    fn invalid_routes() {
+        for r in [
+            route("t", None, None, &["x"]),
+            route("t", Some("kind"), Some("type"), &["x"]),
+        ] {
+            assert!(Router::new("default".to_owned(), vec![r]).is_err());
+        }
+    }
+}
@@ -11,26 +11,125 @@ pub struct Ingester {
     pub decoder: String,
     /// address of bootstrap kafka broker
     pub kafka_broker: String,
+    /// librdkafka consumer properties overriding defaults,
+    /// e.g. `{ "isolation.level" = "read_committed" }`. Security properties
+    /// (`security.*`, `sasl.*`, `ssl.*`) also apply to dead-letter producer.
+    /// `group.id` is set by `consumer_group` instead
+    pub kafka: Option<HashMap<String, String>>,
     /// topic to ingest
-    pub topic: String,
+    pub topic: Option<String>,
+    /// topics to ingest, alternative to `topic`
+    pub topics: Option<Vec<String>>,
+    /// regex of topics to ingest, e.g. "^events\\..*", in addition to `topic` or `topics`.
+    /// Decoders of matched topics are loaded on their first message, ingester fails if that fails
+    pub topic_pattern: Option<String>,
     /// consumer group to use (default: use ingester's name)
     pub consumer_group: Option<String>,
     /// max ClickHouse insert batch size (default: 1000)
     pub batch_size: Option<usize>,
     /// batching timeout (default: 10s)
     pub batch_timeout_seconds: Option<u64>,
+    /// number of threads decoding received messages in parallel, e.g. for heavy Avro or Protobuf
+    /// topics, rows are still batched in order of messages (default: 1)
+    pub decode_parallelism: Option<usize>,
+    /// max number of batches inserted concurrently, while next ones are consumed (default: 4)
+    pub max_concurrent_flushes: Option<usize>,
+    /// max size of payloads of messages pending insert, after which consuming pauses until
+    /// batches being inserted are done, or the largest batch is flushed if there are none
+    /// (default: 256 MiB)
+    pub max_pending_bytes: Option<usize>,
     /// URL of ClickHouse
     pub clickhouse_url: String,
-    /// ClickHouse table to ingest into
+    /// ClickHouse connection settings, overriding parameters of `clickhouse_url`
+    pub clickhouse: Option<ClickHouse>,
+    /// ClickHouse table to ingest into, if row is not matched by any of `routes`
     pub clickhouse_table: String,
+    /// rules routing rows to other tables, first matching rule wins (default: none)
+    pub routes: Option<Vec<Route>>,
+    /// insert batches with `insert_deduplication_token` derived from offsets of their messages,
+    /// batching each partition separately and reproducing batches after restart, so that retried
+    /// and replayed inserts are deduplicated by ClickHouse. Tables must have deduplication enabled,
+    /// e.g. Replicated*MergeTree or `non_replicated_deduplication_window` setting (default: false)
+    pub exactly_once: Option<bool>,
+    /// ClickHouse table to store offsets of partitions in with inserted rows, which are used instead of
+    /// offsets committed to Kafka when partitions are assigned. Table must have columns
+    /// `consumer_group String, topic String, partition Int32, offset Int64` (default: none)
+    pub offsets_table: Option<String>,
+    /// topic to send messages that failed to decode (default: none, such messages are dropped)
+    pub dead_letter_topic: Option<String>,
+    /// columns filled from message metadata: map of column names to `_key`, `_topic`,
+    /// `_partition`, `_offset`, `_timestamp` or `_header.<name>` (default: none)
+    pub metadata_columns: Option<HashMap<String, String>>,
+    /// what to do with tombstones (messages without payload): "skip", "dead-letter", or "delete",
+    /// which inserts row of `metadata_columns` with `tombstone_column` set to 1 (default: "skip")
+    pub tombstones: Option<String>,
+    /// column marking deleted rows, e.g. `is_deleted` of ReplacingMergeTree (default: "is_deleted")
+    pub tombstone_column: Option<String>,
     /// Decoder-specific configuration
     pub custom: Option<toml::Value>,
 }
 
+/// ClickHouse connection settings
+#[derive(Deserialize)]
+pub struct ClickHouse {
+    /// user name (default: "default")
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// name of environment variable to read password from
+    pub password_env: Option<String>,
+    /// file to read password from
+    pub password_file: Option<String>,
+    /// use TLS (default: false). Custom CA cannot be configured, as the driver doesn't export
+    /// its certificate type, so server certificate must be trusted by the system,
+    /// e.g. via `SSL_CERT_FILE` variable
+    pub secure: Option<bool>,
+    /// skip verification of server certificate (default: false)
+    pub skip_verify: Option<bool>,
+    /// "lz4" or "none" (default: "none")
+    pub compression: Option<String>,
+    /// min number of idle connections (default: 10)
+    pub pool_min: Option<usize>,
+    /// max number of connections (default: 20)
+    pub pool_max: Option<usize>,
+    /// timeout of establishing connection (default: 500)
+    pub connection_timeout_ms: Option<u64>,
+    /// timeout of queries and inserts (default: 180)
+    pub query_timeout_seconds: Option<u64>,
+    /// delay before first retry of failed insert, doubled with each following retry (default: 1000)
+    pub retry_backoff_ms: Option<u64>,
+    /// max delay between retries of failed insert (default: 60)
+    pub retry_max_backoff_seconds: Option<u64>,
+    /// max attempts to insert rows, after which ingester fails, so that it's restarted
+    /// from the last committed offsets (default: unlimited)
+    pub retry_max_attempts: Option<usize>,
+    /// replicas, e.g. "host2:9000", connections are balanced between them and `clickhouse_url`
+    /// host in round-robin, and failed insert is retried on the next replica without backoff
+    pub alt_hosts: Option<Vec<String>>,
+}
+
+/// rule routing rows to ClickHouse table by value of decoded column, message header or key
+#[derive(Deserialize)]
+pub struct Route {
+    /// table to ingest matching rows into
+    pub table: String,
+    /// name of decoded column to match
+    pub column: Option<String>,
+    /// name of message header to match
+    pub header: Option<String>,
+    /// match message key (default: false)
+    pub key: Option<bool>,
+    /// values to match
+    pub values: Vec<String>,
+}
+
 #[derive(Deserialize)]
 pub struct Settings {
     /// Map of ingester names and settings
     pub ingesters: HashMap<String, Ingester>,
+    /// address to serve prometheus metrics on, e.g. "0.0.0.0:9090" (default: metrics are not served)
+    pub metrics_address: Option<String>,
+    /// time to flush pending rows and commit offsets on SIGTERM or SIGINT before exiting (default: 20s)
+    pub shutdown_timeout_seconds: Option<u64>,
 }
 
 impl Settings {